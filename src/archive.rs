@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::config::{state_file_name, state_path, write_private_file};
+use crate::rockbox::playback_line_date;
+
+const ARCHIVE_EXTENSION: &str = ".log.gz";
+
+pub fn archive_dir(config_path: &Path, id: &str) -> PathBuf {
+    state_path(config_path, "archive").join(state_file_name(id))
+}
+
+// The delivery ledger's name for the archive of `id`, under which plays
+// delivered before their lines were archived are remembered.
+pub fn archive_ledger_id(id: &str) -> String {
+    format!("archive:{id}")
+}
+
+// Files each line under the day it was played; lines without a timestamp go
+// to `fallback`. Returns the files that were written.
+pub fn archive_lines(dir: &Path, fallback: NaiveDate, text: &str) -> Result<Vec<PathBuf>> {
+    let mut days: BTreeMap<NaiveDate, String> = BTreeMap::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let day = days
+            .entry(playback_line_date(line).unwrap_or(fallback))
+            .or_default();
+        day.push_str(line);
+        day.push('\n');
+    }
+    days.into_iter()
+        .map(|(date, lines)| append_day(dir, date, &lines))
+        .collect()
+}
+
+// Each call adds one gzip member to the file for `date`; concatenated members
+// form a valid gzip stream, so earlier data is never recompressed.
+fn append_day(dir: &Path, date: NaiveDate, text: &str) -> Result<PathBuf> {
+    let path = dir.join(format!("{date}{ARCHIVE_EXTENSION}"));
+    let mut archived = if path.exists() {
+        fs::read(&path).with_context(|| format!("Failed reading archive {}", path.display()))?
+    } else {
+        Vec::new()
+    };
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(text.as_bytes())
+        .context("Failed compressing archived lines")?;
+    archived.extend(
+        encoder
+            .finish()
+            .context("Failed compressing archived lines")?,
+    );
+    write_private_file(&path, &archived)
+        .with_context(|| format!("Failed writing archive {}", path.display()))?;
+    Ok(path)
+}
+
+// Returns the archived lines of the days between `from` and `to`, oldest first.
+pub fn read_archive(dir: &Path, from: Option<NaiveDate>, to: Option<NaiveDate>) -> Result<String> {
+    if !dir.exists() {
+        return Ok(String::new());
+    }
+    let mut days = Vec::new();
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("Failed reading archive {}", dir.display()))?
+    {
+        let path = entry?.path();
+        let Some(date) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(ARCHIVE_EXTENSION))
+            .and_then(|stem| stem.parse::<NaiveDate>().ok())
+        else {
+            continue;
+        };
+        if from.is_none_or(|from| date >= from) && to.is_none_or(|to| date <= to) {
+            days.push((date, path));
+        }
+    }
+    days.sort();
+    let mut text = String::new();
+    for (_, path) in days {
+        let compressed = fs::read(&path)
+            .with_context(|| format!("Failed reading archive {}", path.display()))?;
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut text)
+            .with_context(|| format!("Failed decompressing archive {}", path.display()))?;
+    }
+    Ok(text)
+}
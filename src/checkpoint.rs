@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{load_state, save_state, state_path};
+
+// Where a run with --no-truncate stopped reading a playback log, so the next
+// run only sees lines appended since.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    pub offset: u64,
+    pub last_line_md5: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoints {
+    #[serde(default)]
+    logs: HashMap<String, Checkpoint>,
+}
+
+impl Checkpoints {
+    pub fn get(&self, log: &str) -> Option<&Checkpoint> {
+        self.logs.get(log)
+    }
+
+    pub fn set(&mut self, log: &str, checkpoint: Checkpoint) {
+        self.logs.insert(log.to_string(), checkpoint);
+    }
+
+    pub fn remove(&mut self, log: &str) -> bool {
+        self.logs.remove(log).is_some()
+    }
+}
+
+pub enum Resume {
+    Start,
+    Offset(u64),
+    Restart(&'static str),
+}
+
+pub struct LogTail {
+    pub text: String,
+    pub resume: Resume,
+    pub checkpoint: Checkpoint,
+}
+
+pub fn checkpoints_path(config_path: &Path) -> PathBuf {
+    state_path(config_path, "checkpoints.json")
+}
+
+pub fn load_checkpoints(path: &Path) -> Result<Checkpoints> {
+    load_state(path, "playback log checkpoints")
+}
+
+pub fn save_checkpoints(checkpoints: &Checkpoints, path: &Path) -> Result<()> {
+    save_state(checkpoints, path, "playback log checkpoints")
+}
+
+// Reads the complete lines after `checkpoint`. A trailing line without a
+// newline may still be being written and is left for the next run.
+pub fn read_log_tail(path: &Path, checkpoint: Option<&Checkpoint>) -> Result<LogTail> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed reading playback log {}", path.display()))?;
+    let (device, inode) = file_identity(path)?;
+    let resume = match checkpoint {
+        None => Resume::Start,
+        Some(checkpoint) => check_resume(checkpoint, &bytes, device, inode),
+    };
+    let start = match resume {
+        Resume::Offset(offset) => usize::try_from(offset).unwrap_or(bytes.len()),
+        Resume::Start | Resume::Restart(_) => 0,
+    };
+    let end = bytes
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |newline| newline + 1)
+        .max(start);
+    Ok(LogTail {
+        text: String::from_utf8_lossy(&bytes[start..end]).into_owned(),
+        resume,
+        checkpoint: Checkpoint {
+            device,
+            inode,
+            offset: end as u64,
+            last_line_md5: last_line_md5(&bytes[..end]),
+        },
+    })
+}
+
+// Where a truncating run should start spooling a log that an earlier
+// --no-truncate run already read part of.
+pub fn resume_log(path: &Path, checkpoint: Option<&Checkpoint>) -> Result<Resume> {
+    let Some(checkpoint) = checkpoint else {
+        return Ok(Resume::Start);
+    };
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed reading playback log {}", path.display()))?;
+    let (device, inode) = file_identity(path)?;
+    Ok(check_resume(checkpoint, &bytes, device, inode))
+}
+
+// FAT drivers do not keep inode numbers across mounts, so a changed identity
+// alone does not mean the log was replaced; the hash of the last line read
+// decides, and the identity only tells a rotation from a rewrite.
+fn check_resume(
+    checkpoint: &Checkpoint,
+    bytes: &[u8],
+    device: Option<u64>,
+    inode: Option<u64>,
+) -> Resume {
+    let same_file = checkpoint.device == device && checkpoint.inode == inode;
+    let Some(offset) = usize::try_from(checkpoint.offset)
+        .ok()
+        .filter(|offset| *offset <= bytes.len())
+    else {
+        return Resume::Restart(if same_file {
+            "was truncated"
+        } else {
+            "was replaced"
+        });
+    };
+    if last_line_md5(&bytes[..offset]) == checkpoint.last_line_md5 {
+        Resume::Offset(checkpoint.offset)
+    } else if same_file {
+        Resume::Restart("was rewritten")
+    } else {
+        Resume::Restart("was replaced")
+    }
+}
+
+fn last_line_md5(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let start = bytes
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    format!("{:x}", md5::compute(&bytes[start..]))
+}
+
+#[cfg(unix)]
+fn file_identity(path: &Path) -> Result<(Option<u64>, Option<u64>)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata =
+        fs::metadata(path).with_context(|| format!("Failed reading {}", path.display()))?;
+    Ok((Some(metadata.dev()), Some(metadata.ino())))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_path: &Path) -> Result<(Option<u64>, Option<u64>)> {
+    Ok((None, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::process;
+
+    use super::*;
+
+    // A scratch playback log, removed when dropped.
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str, contents: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("cobblestone-{name}-{}.log", process::id()));
+            fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        fn append(&self, contents: &str) {
+            let mut file = OpenOptions::new().append(true).open(&self.0).unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+        }
+
+        // Rewrites the file in place, keeping its identity.
+        fn rewrite(&self, contents: &str) {
+            fs::write(&self.0, contents).unwrap();
+        }
+
+        fn tail(&self, checkpoint: Option<&Checkpoint>) -> LogTail {
+            read_log_tail(&self.0, checkpoint).unwrap()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn resumes_after_appended_lines() {
+        let log = TempLog::new("checkpoint-append", "1:a\n2:b\n");
+        let first = log.tail(None);
+        assert!(matches!(first.resume, Resume::Start));
+        assert_eq!(first.text, "1:a\n2:b\n");
+
+        log.append("3:c\n");
+        let second = log.tail(Some(&first.checkpoint));
+        assert!(matches!(second.resume, Resume::Offset(8)));
+        assert_eq!(second.text, "3:c\n");
+        assert_eq!(second.checkpoint.offset, 12);
+    }
+
+    #[test]
+    fn holds_back_a_line_without_a_newline() {
+        let log = TempLog::new("checkpoint-partial", "1:a\n2:b");
+        let first = log.tail(None);
+        assert_eq!(first.text, "1:a\n");
+        assert_eq!(first.checkpoint.offset, 4);
+
+        log.append("\n");
+        let second = log.tail(Some(&first.checkpoint));
+        assert!(matches!(second.resume, Resume::Offset(4)));
+        assert_eq!(second.text, "2:b\n");
+    }
+
+    #[test]
+    fn restarts_a_truncated_log() {
+        let log = TempLog::new("checkpoint-truncated", "1:a\n2:b\n");
+        let first = log.tail(None);
+
+        log.rewrite("3:c\n");
+        let second = log.tail(Some(&first.checkpoint));
+        assert!(matches!(second.resume, Resume::Restart("was truncated")));
+        assert_eq!(second.text, "3:c\n");
+    }
+
+    #[test]
+    fn restarts_a_log_whose_last_line_was_rewritten() {
+        let log = TempLog::new("checkpoint-rewritten", "1:a\n2:b\n");
+        let first = log.tail(None);
+
+        log.rewrite("1:a\n2:x\n3:c\n");
+        let second = log.tail(Some(&first.checkpoint));
+        assert!(matches!(second.resume, Resume::Restart("was rewritten")));
+        assert_eq!(second.text, "1:a\n2:x\n3:c\n");
+    }
+
+    #[test]
+    fn resumes_a_log_with_a_new_identity_when_the_last_line_matches() {
+        let checkpoint = Checkpoint {
+            device: Some(1),
+            inode: Some(2),
+            offset: 4,
+            last_line_md5: last_line_md5(b"1:a\n"),
+        };
+        let resume = check_resume(&checkpoint, b"1:a\n2:b\n", Some(1), Some(3));
+        assert!(matches!(resume, Resume::Offset(4)));
+        let resume = check_resume(&checkpoint, b"1:x\n2:b\n", Some(1), Some(3));
+        assert!(matches!(resume, Resume::Restart("was replaced")));
+    }
+}
@@ -1,31 +1,199 @@
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::vault::{self, Vault, read_passphrase};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceKeys {
     pub api_key: String,
     pub api_secret: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
     pub service: String,
     pub username: String,
-    pub password_md5: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_md5: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_root: Option<String>,
+    #[serde(default, skip_serializing_if = "EligibilityOverrides::is_empty")]
+    pub eligibility: EligibilityOverrides,
+}
+
+impl Account {
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.service, self.username)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EligibilityOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_track_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_played_percent: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_required_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_only: Option<bool>,
+}
+
+impl EligibilityOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.min_track_seconds.is_none()
+            && self.min_played_percent.is_none()
+            && self.max_required_seconds.is_none()
+            && self.finished_only.is_none()
+    }
+
+    pub fn merge(&mut self, other: &EligibilityOverrides) {
+        self.min_track_seconds = other.min_track_seconds.or(self.min_track_seconds);
+        self.min_played_percent = other.min_played_percent.or(self.min_played_percent);
+        self.max_required_seconds = other.max_required_seconds.or(self.max_required_seconds);
+        self.finished_only = other.finished_only.or(self.finished_only);
+    }
+}
+
+// Plays matching every pattern a rule sets are never scrobbled. Patterns are
+// case-insensitive globs; `path` matches the path logged by Rockbox, the rest
+// match tagcache fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExclusionRule {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    // Account ids (`service:username`); empty means every account.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RewriteField {
+    Artist,
+    Title,
+    Album,
+}
+
+impl RewriteField {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RewriteField::Artist => "artist",
+            RewriteField::Title => "title",
+            RewriteField::Album => "album",
+        }
+    }
+}
+
+impl FromStr for RewriteField {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "artist" => Ok(RewriteField::Artist),
+            "title" => Ok(RewriteField::Title),
+            "album" => Ok(RewriteField::Album),
+            _ => bail!("Unknown field {value}; use artist, title or album"),
+        }
+    }
+}
+
+// Rewrites one field of every scrobble, either when it equals `exact` or by
+// replacing each match of `regex` (which may refer to groups as `$1`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewriteRule {
+    pub field: RewriteField,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exact: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rockbox_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_label: Option<String>,
+    // Account ids (`service:username`); empty means every account.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    // Stamp scrobbles with the logged time instead of when the listen started.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub logged_timestamps: bool,
+    // Overrides the global `max_pause_seconds`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pause_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "EligibilityOverrides::is_empty")]
+    pub eligibility: EligibilityOverrides,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub version: u32,
     #[serde(default)]
     pub services: HashMap<String, ServiceKeys>,
     #[serde(default)]
     pub accounts: Vec<Account>,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    // Applies to every run; device and account overrides take precedence.
+    #[serde(default, skip_serializing_if = "EligibilityOverrides::is_empty")]
+    pub eligibility: EligibilityOverrides,
+    // Longest pause between two logged fragments that are joined into one
+    // listen (default: 30 minutes).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pause_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclusions: Vec<ExclusionRule>,
+    // Applied in order, each to the result of the previous ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrites: Vec<RewriteRule>,
+    #[serde(skip)]
+    pub passphrase: Option<String>,
 }
 
+// Sections moved into the encrypted vault when a passphrase is set.
+const SECRET_SECTIONS: &[&str] = &["services", "accounts"];
+const VAULT_SECTION: &str = "vault";
+
+pub const CONFIG_VERSION: u32 = 1;
+
+// Each migration upgrades a config from version `index` to `index + 1`. They
+// run on the raw JSON, after the vault has been opened, so a layout change
+// never has to stay readable by the typed `Config`.
+const MIGRATIONS: &[fn(&mut Map<String, Value>)] = &[migrate_v0_credentials];
+
 pub fn default_config_path() -> PathBuf {
     let fallback = PathBuf::from(".config/cobblestone/config.json");
     dirs::home_dir().map_or(fallback, |home| {
@@ -39,46 +207,288 @@ pub fn load_config(path: &Path) -> Result<Config> {
     }
     let raw = fs::read_to_string(path)
         .with_context(|| format!("Failed reading config at {}", path.display()))?;
-    let config = serde_json::from_str(&raw)
+    let mut value: Value = serde_json::from_str(&raw)
         .with_context(|| format!("Failed parsing config at {}", path.display()))?;
+    let vault = value
+        .as_object_mut()
+        .and_then(|object| object.remove(VAULT_SECTION));
+    let passphrase = match vault {
+        Some(vault) => {
+            let vault: Vault =
+                serde_json::from_value(vault).context("Failed parsing credential vault")?;
+            let passphrase = read_passphrase(false)?;
+            let secrets: Map<String, Value> =
+                serde_json::from_slice(&vault::open(&vault, &passphrase)?)
+                    .context("Failed parsing decrypted credentials")?;
+            if let Some(object) = value.as_object_mut() {
+                object.extend(secrets);
+            }
+            Some(passphrase)
+        }
+        None => None,
+    };
+    if let Some(object) = value.as_object_mut() {
+        migrate_config(object, path)?;
+    }
+    let mut config: Config = serde_json::from_value(value)
+        .with_context(|| format!("Failed parsing config at {}", path.display()))?;
+    config.passphrase = passphrase;
     Ok(config)
 }
 
+fn migrate_config(object: &mut Map<String, Value>, path: &Path) -> Result<()> {
+    let version = match object.get("version") {
+        None => 0,
+        Some(value) => value
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .with_context(|| format!("Invalid config version {value} in {}", path.display()))?,
+    };
+    if version > CONFIG_VERSION {
+        bail!(
+            "Config version {version} in {} is newer than this cobblestone supports ({CONFIG_VERSION})",
+            path.display()
+        );
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(object);
+    }
+    object.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    Ok(())
+}
+
+// Version 0 accounts always carried a `password_md5`, left empty or null when
+// no password was stored; later layouts omit the field instead.
+fn migrate_v0_credentials(object: &mut Map<String, Value>) {
+    let Some(accounts) = object.get_mut("accounts").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for account in accounts.iter_mut().filter_map(Value::as_object_mut) {
+        for field in ["password_md5", "session_key", "token", "api_root"] {
+            if account
+                .get(field)
+                .is_some_and(|value| value.is_null() || value.as_str() == Some(""))
+            {
+                account.remove(field);
+            }
+        }
+    }
+}
+
 pub fn save_config(config: &Config, path: &Path) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("Failed creating config directory {}", parent.display()))?;
+    let mut value = serde_json::to_value(config).context("Failed serializing config to JSON")?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    }
+    if let Some(passphrase) = &config.passphrase
+        && let Some(object) = value.as_object_mut()
+    {
+        let mut secrets = Map::new();
+        for section in SECRET_SECTIONS {
+            if let Some(section_value) = object.remove(*section) {
+                secrets.insert((*section).to_string(), section_value);
+            }
+        }
+        let plaintext = serde_json::to_vec(&secrets).context("Failed serializing credentials")?;
+        let vault = vault::seal(&plaintext, passphrase)?;
+        object.insert(
+            VAULT_SECTION.to_string(),
+            serde_json::to_value(vault).context("Failed serializing credential vault")?,
+        );
     }
     let serialized =
-        serde_json::to_string_pretty(config).context("Failed serializing config to JSON")?;
-    fs::write(path, format!("{serialized}\n"))
-        .with_context(|| format!("Failed writing config at {}", path.display()))?;
+        serde_json::to_string_pretty(&value).context("Failed serializing config to JSON")?;
+    write_private_file(path, format!("{serialized}\n").as_bytes())
+        .with_context(|| format!("Failed writing config at {}", path.display()))
+}
+
+pub fn state_path(config_path: &Path, name: &str) -> PathBuf {
+    config_path.with_file_name(name)
+}
+
+// Turns a device name or log path into a single safe file name component.
+pub fn state_file_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '.' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    name.trim_matches('_').to_string()
+}
+
+pub fn load_state<T: DeserializeOwned + Default>(path: &Path, what: &str) -> Result<T> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("Failed reading {what} at {}", path.display()))?;
+    let state = serde_json::from_str(&raw)
+        .with_context(|| format!("Failed parsing {what} at {}", path.display()))?;
+    Ok(state)
+}
+
+pub fn save_state<T: Serialize>(state: &T, path: &Path, what: &str) -> Result<()> {
+    let serialized = serde_json::to_string_pretty(state)
+        .with_context(|| format!("Failed serializing {what} to JSON"))?;
+    write_private_file(path, format!("{serialized}\n").as_bytes())
+        .with_context(|| format!("Failed writing {what} at {}", path.display()))
+}
+
+// Writes to a sibling temp file and renames it over `path`, so a crash leaves
+// either the old or the new contents. The file is readable by its owner only.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)
+        .with_context(|| format!("Failed creating directory {}", parent.display()))?;
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Invalid file path {}", path.display()))?;
+    let temp_path = parent.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let result = write_and_sync(&temp_path, contents).and_then(|()| {
+        fs::rename(&temp_path, path).with_context(|| format!("Failed replacing {}", path.display()))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+    // Persist the rename itself; not every platform can open a directory.
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
     Ok(())
 }
 
+fn write_and_sync(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed creating {}", path.display()))?;
+    file.write_all(contents)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed writing {}", path.display()))
+}
+
 pub fn set_service_keys(config: &mut Config, service: &str, api_key: &str, api_secret: &str) {
+    let base_url = config
+        .services
+        .get(service)
+        .and_then(|keys| keys.base_url.clone());
     config.services.insert(
         service.to_string(),
         ServiceKeys {
             api_key: api_key.to_string(),
             api_secret: api_secret.to_string(),
+            base_url,
         },
     );
 }
 
+pub fn add_custom_service(
+    config: &mut Config,
+    service: &str,
+    base_url: &str,
+    api_key: &str,
+    api_secret: &str,
+) {
+    config.services.insert(
+        service.to_string(),
+        ServiceKeys {
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            base_url: Some(base_url.to_string()),
+        },
+    );
+}
+
+pub fn remove_custom_service(config: &mut Config, service: &str) -> bool {
+    if config
+        .services
+        .get(service)
+        .is_none_or(|keys| keys.base_url.is_none())
+    {
+        return false;
+    }
+    config.services.remove(service);
+    true
+}
+
 pub fn add_account(config: &mut Config, service: &str, username: &str, password: &str) {
-    let password_md5 = format!("{:x}", md5::compute(password));
-    for account in &mut config.accounts {
-        if account.service == service && account.username == username {
-            account.password_md5 = password_md5;
-            return;
+    let account = find_or_insert_account(config, service, username);
+    account.password_md5 = Some(format!("{:x}", md5::compute(password)));
+    account.session_key = None;
+}
+
+pub fn add_session_account(config: &mut Config, service: &str, username: &str, session_key: &str) {
+    let account = find_or_insert_account(config, service, username);
+    account.session_key = Some(session_key.to_string());
+    account.password_md5 = None;
+}
+
+pub fn add_token_account(
+    config: &mut Config,
+    service: &str,
+    username: &str,
+    token: &str,
+    api_root: Option<&str>,
+) {
+    let account = find_or_insert_account(config, service, username);
+    account.token = Some(token.to_string());
+    account.api_root = api_root.map(str::to_string);
+}
+
+fn find_or_insert_account<'a>(
+    config: &'a mut Config,
+    service: &str,
+    username: &str,
+) -> &'a mut Account {
+    let position = config
+        .accounts
+        .iter()
+        .position(|account| account.service == service && account.username == username);
+    let index = position.unwrap_or_else(|| {
+        config.accounts.push(Account {
+            service: service.to_string(),
+            username: username.to_string(),
+            password_md5: None,
+            session_key: None,
+            token: None,
+            api_root: None,
+            eligibility: EligibilityOverrides::default(),
+        });
+        config.accounts.len() - 1
+    });
+    &mut config.accounts[index]
+}
+
+pub fn update_session_keys(config: &mut Config, accounts: &[Account]) -> bool {
+    let mut changed = false;
+    for updated in accounts {
+        let Some(account) = config.accounts.iter_mut().find(|account| {
+            account.service == updated.service && account.username == updated.username
+        }) else {
+            continue;
+        };
+        if account.session_key != updated.session_key {
+            account.session_key.clone_from(&updated.session_key);
+            changed = true;
         }
     }
-    config.accounts.push(Account {
-        service: service.to_string(),
-        username: username.to_string(),
-        password_md5,
-    });
+    changed
 }
 
 pub fn remove_account(config: &mut Config, service: &str, username: &str) -> bool {
@@ -86,9 +496,79 @@ pub fn remove_account(config: &mut Config, service: &str, username: &str) -> boo
     config
         .accounts
         .retain(|account| !(account.service == service && account.username == username));
+    let id = format!("{service}:{username}");
+    for device in &mut config.devices {
+        device.accounts.retain(|account| *account != id);
+    }
+    // A rule scoped to the removed account alone must not widen to every account.
+    config.exclusions.retain_mut(|rule| {
+        let scoped = !rule.accounts.is_empty();
+        rule.accounts.retain(|account| *account != id);
+        !scoped || !rule.accounts.is_empty()
+    });
     config.accounts.len() != original_len
 }
 
+pub fn add_device(config: &mut Config, device: Device) {
+    if let Some(existing) = config
+        .devices
+        .iter_mut()
+        .find(|existing| existing.name == device.name)
+    {
+        *existing = device;
+    } else {
+        config.devices.push(device);
+    }
+}
+
+pub fn remove_device(config: &mut Config, name: &str) -> bool {
+    let original_len = config.devices.len();
+    config.devices.retain(|device| device.name != name);
+    config.devices.len() != original_len
+}
+
+pub fn add_exclusion(config: &mut Config, rule: ExclusionRule) {
+    if let Some(existing) = config
+        .exclusions
+        .iter_mut()
+        .find(|existing| existing.name == rule.name)
+    {
+        *existing = rule;
+    } else {
+        config.exclusions.push(rule);
+    }
+}
+
+pub fn remove_exclusion(config: &mut Config, name: &str) -> bool {
+    let original_len = config.exclusions.len();
+    config.exclusions.retain(|rule| rule.name != name);
+    config.exclusions.len() != original_len
+}
+
+pub fn find_device<'a>(config: &'a Config, name: &str) -> Result<&'a Device> {
+    config
+        .devices
+        .iter()
+        .find(|device| device.name == name)
+        .with_context(|| format!("No device named {name}"))
+}
+
+pub fn find_device_mut<'a>(config: &'a mut Config, name: &str) -> Result<&'a mut Device> {
+    config
+        .devices
+        .iter_mut()
+        .find(|device| device.name == name)
+        .with_context(|| format!("No device named {name}"))
+}
+
+pub fn find_account_mut<'a>(config: &'a mut Config, id: &str) -> Result<&'a mut Account> {
+    config
+        .accounts
+        .iter_mut()
+        .find(|account| account.id() == id)
+        .with_context(|| format!("No account {id} configured; use SERVICE:USERNAME."))
+}
+
 pub fn iter_accounts<'a>(
     config: &'a Config,
     service: Option<&str>,
@@ -0,0 +1,345 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::{Config, Device};
+use crate::rockbox::{RockboxInfo, read_rockbox_info};
+
+const MOUNTS_FILE: &str = "proc/self/mounts";
+const LABELS_DIR: &str = "dev/disk/by-label";
+// Desktop automounters mount removable media at /media/<label>,
+// /media/<user>/<label> or /run/media/<user>/<label>.
+const MEDIA_DIRS: &[(&str, usize)] = &[("media", 2), ("run/media", 2)];
+const ROCKBOX_DIR: &str = ".rockbox";
+const TAGCACHE_FILE: &str = "database_idx.tcd";
+const PLAYBACK_LOG: &str = "playback.log";
+
+pub struct Mount {
+    pub source: String,
+    pub mount_point: PathBuf,
+}
+
+pub struct Player {
+    pub mount_point: PathBuf,
+    pub rockbox_dir: PathBuf,
+    pub info: Option<RockboxInfo>,
+    pub has_playback_log: bool,
+}
+
+impl Player {
+    pub fn describe(&self) -> String {
+        self.info.as_ref().map_or_else(
+            || "unknown Rockbox build".to_string(),
+            RockboxInfo::describe,
+        )
+    }
+}
+
+// Every path is resolved below `root`, which is `/` outside of tests; mount
+// points read from the mount table are re-rooted the same way.
+pub fn read_mounts(root: &Path) -> Result<Vec<Mount>> {
+    let path = root.join(MOUNTS_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let raw = fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading mount table {}", path.display()))?;
+    Ok(raw
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            let mount_point = fields.next()?;
+            Some(Mount {
+                source: unescape_mount_field(source),
+                mount_point: under_root(root, Path::new(&unescape_mount_field(mount_point))),
+            })
+        })
+        .collect())
+}
+
+pub fn discover_players(root: &Path) -> Result<Vec<Player>> {
+    let mut candidates: Vec<PathBuf> = read_mounts(root)?
+        .into_iter()
+        .map(|mount| mount.mount_point)
+        .collect();
+    for (media_dir, depth) in MEDIA_DIRS {
+        collect_dirs(&root.join(media_dir), *depth, &mut candidates);
+    }
+    let mut players: Vec<Player> = Vec::new();
+    for mount_point in candidates {
+        let rockbox_dir = mount_point.join(ROCKBOX_DIR);
+        if !rockbox_dir.join(TAGCACHE_FILE).is_file()
+            || players
+                .iter()
+                .any(|player| same_path(&player.mount_point, &mount_point))
+        {
+            continue;
+        }
+        players.push(Player {
+            info: read_rockbox_info(&rockbox_dir).ok().flatten(),
+            has_playback_log: rockbox_dir.join(PLAYBACK_LOG).is_file(),
+            mount_point,
+            rockbox_dir,
+        });
+    }
+    Ok(players)
+}
+
+// A filesystem label matches a mount when udev's by-label link points at the
+// mounted block device, or when the desktop automounter named the mount point
+// after the label (/media/<user>/<label>, /run/media/<user>/<label>).
+pub fn find_mount_by_label(root: &Path, label: &str) -> Result<Option<PathBuf>> {
+    let mounts = read_mounts(root)?;
+    let device = root
+        .join(LABELS_DIR)
+        .join(escape_label(label))
+        .canonicalize()
+        .ok();
+    if let Some(device) = device
+        && let Some(mount) = mounts.iter().find(|mount| {
+            under_root(root, Path::new(&mount.source))
+                .canonicalize()
+                .is_ok_and(|source| source == device)
+        })
+    {
+        return Ok(Some(mount.mount_point.clone()));
+    }
+    let mut candidates: Vec<PathBuf> = mounts.into_iter().map(|mount| mount.mount_point).collect();
+    for (media_dir, depth) in MEDIA_DIRS {
+        collect_dirs(&root.join(media_dir), *depth, &mut candidates);
+    }
+    Ok(candidates
+        .into_iter()
+        .find(|mount_point| mount_point.file_name().is_some_and(|name| name == label)))
+}
+
+pub fn device_rockbox_dir(root: &Path, device: &Device) -> Result<PathBuf> {
+    if let Some(rockbox_dir) = &device.rockbox_dir {
+        return Ok(rockbox_dir.clone());
+    }
+    let Some(label) = &device.mount_label else {
+        bail!("Device {} has no rockbox dir or mount label", device.name);
+    };
+    match find_mount_by_label(root, label)? {
+        Some(mount_point) => Ok(mount_point.join(ROCKBOX_DIR)),
+        None => bail!("Device {} ({label}) is not mounted", device.name),
+    }
+}
+
+pub fn matching_device<'a>(root: &Path, config: &'a Config, player: &Player) -> Option<&'a Device> {
+    config.devices.iter().find(|device| {
+        device_rockbox_dir(root, device).is_ok_and(|dir| same_path(&dir, &player.rockbox_dir))
+    })
+}
+
+fn collect_dirs(dir: &Path, depth: usize, found: &mut Vec<PathBuf>) {
+    if depth == 0 {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_dirs(&path, depth - 1, found);
+            found.push(path);
+        }
+    }
+}
+
+fn under_root(root: &Path, path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .fold(root.to_path_buf(), |joined, component| {
+            joined.join(component)
+        })
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// The kernel escapes space, tab, newline and backslash as octal in the mount
+// table.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'\\'
+            && let Some(code) = field
+                .get(index + 1..index + 4)
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+        {
+            unescaped.push(code);
+            index += 4;
+        } else {
+            unescaped.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+// udev escapes unsafe characters in /dev/disk/by-label names as \xNN.
+fn escape_label(label: &str) -> String {
+    label
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"#+-.:=@_".contains(&byte) {
+                char::from(byte).to_string()
+            } else {
+                format!("\\x{byte:02x}")
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use super::*;
+    use crate::config::EligibilityOverrides;
+
+    // A scratch directory standing in for `/`, removed when dropped.
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cobblestone-{name}-{}", process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, relative: &str, contents: &str) {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn add_player(&self, mount_point: &str, with_log: bool) {
+            self.write(&format!("{mount_point}/.rockbox/{TAGCACHE_FILE}"), "");
+            if with_log {
+                self.write(&format!("{mount_point}/.rockbox/{PLAYBACK_LOG}"), "");
+            }
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn labelled(name: &str, label: &str) -> Device {
+        Device {
+            name: name.to_string(),
+            rockbox_dir: None,
+            mount_label: Some(label.to_string()),
+            accounts: Vec::new(),
+            timezone: None,
+            logged_timestamps: false,
+            max_pause_seconds: None,
+            eligibility: EligibilityOverrides::default(),
+        }
+    }
+
+    #[test]
+    fn discovers_players_from_the_mount_table_and_media_dirs() {
+        let root = TempRoot::new("discover");
+        root.write(
+            MOUNTS_FILE,
+            "/dev/sda1 / ext4 rw 0 0\n\
+             /dev/sdb1 /mnt/my\\040player vfat rw 0 0\n\
+             /dev/sdc1 /media/user/IPOD vfat rw 0 0\n",
+        );
+        root.add_player("mnt/my player", true);
+        root.add_player("media/user/IPOD", false);
+        root.add_player("run/media/user/SANSA", true);
+        // Mounted, but not a Rockbox player.
+        fs::create_dir_all(root.0.join("media/user/USB/.rockbox")).unwrap();
+
+        let mut players = discover_players(&root.0).unwrap();
+        players.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+        let found: Vec<_> = players
+            .iter()
+            .map(|player| (player.mount_point.clone(), player.has_playback_log))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (root.0.join("media/user/IPOD"), false),
+                (root.0.join("mnt/my player"), true),
+                (root.0.join("run/media/user/SANSA"), true),
+            ]
+        );
+        assert_eq!(
+            players[1].rockbox_dir,
+            root.0.join("mnt/my player/.rockbox")
+        );
+    }
+
+    #[test]
+    fn discovers_nothing_without_mounts() {
+        let root = TempRoot::new("discover-empty");
+        assert!(discover_players(&root.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_a_mount_by_its_udev_label() {
+        let root = TempRoot::new("label-udev");
+        root.write(MOUNTS_FILE, "/dev/sdb1 /mnt/player vfat rw 0 0\n");
+        root.write("dev/sdb1", "");
+        fs::create_dir_all(root.0.join(LABELS_DIR)).unwrap();
+        symlink(
+            root.0.join("dev/sdb1"),
+            root.0.join(LABELS_DIR).join("MY\\x20PLAYER"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            find_mount_by_label(&root.0, "MY PLAYER").unwrap(),
+            Some(root.0.join("mnt/player"))
+        );
+        assert_eq!(find_mount_by_label(&root.0, "OTHER").unwrap(), None);
+    }
+
+    #[test]
+    fn finds_a_mount_named_after_its_label() {
+        let root = TempRoot::new("label-media");
+        root.add_player("run/media/user/SANSA", true);
+
+        assert_eq!(
+            find_mount_by_label(&root.0, "SANSA").unwrap(),
+            Some(root.0.join("run/media/user/SANSA"))
+        );
+        let device = labelled("sansa", "SANSA");
+        assert_eq!(
+            device_rockbox_dir(&root.0, &device).unwrap(),
+            root.0.join("run/media/user/SANSA/.rockbox")
+        );
+    }
+
+    #[test]
+    fn reports_an_unmounted_device() {
+        let root = TempRoot::new("label-missing");
+        let device = labelled("ipod", "IPOD");
+        let err = device_rockbox_dir(&root.0, &device).unwrap_err();
+        assert_eq!(err.to_string(), "Device ipod (IPOD) is not mounted");
+    }
+
+    #[test]
+    fn unescapes_mount_fields_and_escapes_labels() {
+        assert_eq!(unescape_mount_field("/mnt/a\\040b\\134c"), "/mnt/a b\\c");
+        assert_eq!(escape_label("MY PLAYER/1"), "MY\\x20PLAYER\\x2f1");
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{Result, anyhow};
+use glob::{MatchOptions, Pattern};
+
+use crate::config::ExclusionRule;
+use crate::scrobble::Candidate;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+// Exclusion rules with their patterns compiled once per run.
+#[derive(Default)]
+pub struct Exclusions {
+    rules: Vec<CompiledRule>,
+}
+
+struct CompiledRule {
+    name: String,
+    accounts: Vec<String>,
+    path: Option<Pattern>,
+    genre: Option<Pattern>,
+    artist: Option<Pattern>,
+    album: Option<Pattern>,
+    comment: Option<Pattern>,
+}
+
+impl Exclusions {
+    pub fn new(rules: &[ExclusionRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let compile = |pattern: &Option<String>| {
+                    pattern
+                        .as_deref()
+                        .map(Pattern::new)
+                        .transpose()
+                        .map_err(|err| {
+                            anyhow!("Invalid pattern in exclusion rule {}: {err}", rule.name)
+                        })
+                };
+                Ok(CompiledRule {
+                    name: rule.name.clone(),
+                    accounts: rule.accounts.clone(),
+                    path: compile(&rule.path)?,
+                    genre: compile(&rule.genre)?,
+                    artist: compile(&rule.artist)?,
+                    album: compile(&rule.album)?,
+                    comment: compile(&rule.comment)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    // Returns the name of the first rule for `account_id` that excludes the
+    // candidate.
+    pub fn matching(&self, candidate: &Candidate, account_id: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                (rule.accounts.is_empty() || rule.accounts.iter().any(|id| id == account_id))
+                    && rule.matches(candidate)
+            })
+            .map(|rule| rule.name.as_str())
+    }
+}
+
+impl CompiledRule {
+    fn matches(&self, candidate: &Candidate) -> bool {
+        let fields = [
+            (&self.path, Some(candidate.entry.path.as_str())),
+            (&self.genre, candidate.genre.as_deref()),
+            (&self.artist, Some(candidate.track.artist.as_str())),
+            (&self.album, candidate.track.album.as_deref()),
+            (&self.comment, candidate.comment.as_deref()),
+        ];
+        let mut constrained = false;
+        for (pattern, value) in fields {
+            let Some(pattern) = pattern else {
+                continue;
+            };
+            constrained = true;
+            if !value.is_some_and(|value| pattern.matches_with(value, MATCH_OPTIONS)) {
+                return false;
+            }
+        }
+        constrained
+    }
+}
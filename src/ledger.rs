@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Account, load_state, save_state, state_path};
+use crate::scrobble::ScrobbleTrack;
+
+// Records which plays of a playback log each account has already received,
+// so a log that is kept after a partial failure is not re-submitted to the
+// accounts that did accept it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    #[serde(default)]
+    logs: HashMap<String, HashMap<String, HashSet<String>>>,
+}
+
+impl Ledger {
+    pub fn is_delivered(&self, log: &str, account: &Account, track: &ScrobbleTrack) -> bool {
+        self.logs
+            .get(log)
+            .and_then(|accounts| accounts.get(&account.id()))
+            .is_some_and(|delivered| delivered.contains(&track.key()))
+    }
+
+    pub fn record(&mut self, log: &str, account: &Account, track: &ScrobbleTrack) {
+        self.logs
+            .entry(log.to_string())
+            .or_default()
+            .entry(account.id())
+            .or_default()
+            .insert(track.key());
+    }
+
+    pub fn retain(&mut self, log: &str, tracks: &[ScrobbleTrack]) {
+        let Some(accounts) = self.logs.get_mut(log) else {
+            return;
+        };
+        let keys: HashSet<String> = tracks.iter().map(ScrobbleTrack::key).collect();
+        for delivered in accounts.values_mut() {
+            delivered.retain(|key| keys.contains(key));
+        }
+        accounts.retain(|_, delivered| !delivered.is_empty());
+        if accounts.is_empty() {
+            self.logs.remove(log);
+        }
+    }
+
+    // Drops the record of `log` once its lines are cleared; when they were
+    // archived, what was delivered moves to the record of `archive`.
+    pub fn release(&mut self, log: &str, archive: Option<&str>) {
+        let Some(accounts) = self.logs.remove(log) else {
+            return;
+        };
+        let Some(archive) = archive else {
+            return;
+        };
+        let archived = self.logs.entry(archive.to_string()).or_default();
+        for (account, keys) in accounts {
+            archived.entry(account).or_default().extend(keys);
+        }
+    }
+}
+
+pub fn ledger_path(config_path: &Path) -> PathBuf {
+    state_path(config_path, "ledger.json")
+}
+
+pub fn log_id(playback_path: &Path) -> String {
+    playback_path
+        .canonicalize()
+        .unwrap_or_else(|_| playback_path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+pub fn load_ledger(path: &Path) -> Result<Ledger> {
+    load_state(path, "delivery ledger")
+}
+
+pub fn save_ledger(ledger: &Ledger, path: &Path) -> Result<()> {
+    save_state(ledger, path, "delivery ledger")
+}
@@ -0,0 +1,115 @@
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+use serde_json::{Map, Value, json};
+
+use crate::config::Account;
+use crate::scrobble::ScrobbleTrack;
+use crate::service::{ApiError, Rejection, Service, with_retry};
+
+pub const MAX_LISTENS_PER_REQUEST: usize = 1000;
+
+pub struct ListenBrainzClient {
+    api_root: String,
+    token: String,
+    http: Client,
+    debug_response: bool,
+}
+
+impl ListenBrainzClient {
+    pub fn new(service: &Service, account: &Account, debug_response: bool) -> Result<Self> {
+        let Some(token) = &account.token else {
+            bail!("No ListenBrainz token stored for {}", account.username);
+        };
+        let http = Client::builder()
+            .build()
+            .context("Failed building HTTP client")?;
+        let api_root = account
+            .api_root
+            .as_deref()
+            .unwrap_or(service.base_url())
+            .trim_end_matches('/')
+            .to_string();
+        Ok(Self {
+            api_root,
+            token: token.clone(),
+            http,
+            debug_response,
+        })
+    }
+
+    pub fn submit_listens(&self, batch: &[ScrobbleTrack]) -> Result<Vec<Option<Rejection>>> {
+        let payload: Vec<Value> = batch.iter().map(listen_payload).collect();
+        let body = json!({
+            "listen_type": "import",
+            "payload": payload,
+        });
+        let url = format!("{}/1/submit-listens", self.api_root);
+        let text = with_retry("Listen submission", || {
+            let response = self
+                .http
+                .post(&url)
+                .header("Authorization", format!("Token {}", self.token))
+                .json(&body)
+                .send()
+                .map_err(|err| ApiError::transport(&err))?;
+            let status = response.status();
+            let text = response.text().map_err(|err| ApiError::transport(&err))?;
+            if !status.is_success() {
+                let mut error = ApiError::from_status(status, true);
+                if let Some(message) = serde_json::from_str::<Value>(&text).ok().and_then(|json| {
+                    json.get("error")
+                        .and_then(Value::as_str)
+                        .map(str::to_string)
+                }) {
+                    error.message = format!("{}: {message}", error.message);
+                }
+                return Err(error.into());
+            }
+            Ok(text)
+        })?;
+        if self.debug_response {
+            eprintln!("Listen response from {url}: {text}");
+        }
+        Ok(vec![None; batch.len()])
+    }
+}
+
+fn listen_payload(track: &ScrobbleTrack) -> Value {
+    let mut additional_info = Map::new();
+    additional_info.insert("media_player".to_string(), json!("Rockbox"));
+    additional_info.insert("submission_client".to_string(), json!("cobblestone"));
+    additional_info.insert(
+        "submission_client_version".to_string(),
+        json!(env!("CARGO_PKG_VERSION")),
+    );
+    if let Some(player) = &track.player {
+        additional_info.insert("media_player_version".to_string(), json!(player.version));
+        additional_info.insert("rockbox_target".to_string(), json!(player.target));
+    }
+    if let Some(track_number) = track.track_number {
+        additional_info.insert("tracknumber".to_string(), json!(track_number));
+    }
+    if track.duration > 0 {
+        additional_info.insert("duration_ms".to_string(), json!(track.duration * 1000));
+    }
+    if let Some(album_artist) = &track.album_artist {
+        additional_info.insert("release_artist_name".to_string(), json!(album_artist));
+    }
+    if let Some(mbid) = &track.mbid {
+        additional_info.insert("recording_mbid".to_string(), json!(mbid));
+    }
+    let mut metadata = Map::new();
+    metadata.insert("artist_name".to_string(), json!(track.artist));
+    metadata.insert("track_name".to_string(), json!(track.title));
+    if let Some(album) = &track.album {
+        metadata.insert("release_name".to_string(), json!(album));
+    }
+    metadata.insert(
+        "additional_info".to_string(),
+        Value::Object(additional_info),
+    );
+    json!({
+        "listened_at": track.timestamp,
+        "track_metadata": metadata,
+    })
+}
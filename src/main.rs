@@ -1,20 +1,62 @@
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use anyhow::{Result, bail};
-use clap::{ArgAction, Parser, Subcommand};
+use anyhow::{Context, Result, bail};
+use chrono::{Local, NaiveDate};
+use chrono_tz::Tz;
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand};
 
+mod archive;
+mod checkpoint;
 mod config;
+mod discovery;
+mod exclusion;
+mod ledger;
+mod listenbrainz;
+mod queue;
+mod rewrite;
 mod rockbox;
 mod scrobble;
 mod service;
+mod spool;
+mod vault;
 
+use crate::archive::{archive_dir, archive_ledger_id, archive_lines, read_archive};
+use crate::checkpoint::{
+    Checkpoint, Resume, checkpoints_path, load_checkpoints, read_log_tail, resume_log,
+    save_checkpoints,
+};
 use crate::config::{
-    ServiceKeys, add_account, default_config_path, get_service_keys, iter_accounts, load_config,
-    remove_account, save_config, set_service_keys,
+    Device, EligibilityOverrides, ExclusionRule, RewriteField, RewriteRule, add_account,
+    add_custom_service, add_device, add_exclusion, add_session_account, add_token_account,
+    default_config_path, find_account_mut, find_device, find_device_mut, iter_accounts,
+    load_config, remove_account, remove_custom_service, remove_device, remove_exclusion,
+    save_config, set_service_keys, update_session_keys,
+};
+use crate::discovery::{Player, device_rockbox_dir, discover_players, matching_device};
+use crate::exclusion::Exclusions;
+use crate::ledger::{Ledger, ledger_path, load_ledger, log_id, save_ledger};
+use crate::queue::{Queue, load_queue, queue_path, save_queue};
+use crate::rewrite::Rewrites;
+use crate::rockbox::{
+    PlaybackEntry, RockboxInfo, TagCache, TagcacheFormat, parse_playback_lines, parse_playback_log,
+    read_rockbox_info,
+};
+use crate::scrobble::{
+    Candidate, EligibilityPolicy, MAX_PAUSE_SECONDS, ScrobbleTrack, SkipReason, TimestampMode,
+    build_scrobble_tracks, select_eligible,
+};
+use crate::service::{
+    BUILTIN_SERVICES, ErrorKind, ScrobbleClient, ScrobbleFailure, Service, default_custom_api_key,
+    fetch_password_session, fetch_web_session, interrupt_submissions, request_auth_token,
 };
-use crate::rockbox::{TagCache, parse_playback_log};
-use crate::scrobble::build_scrobble_tracks;
-use crate::service::{ScrobbleClient, Service};
+use crate::spool::{clear_spool, merge_playback_log, spool_path, spool_playback_log};
+use crate::vault::read_passphrase;
+
+const DEFAULT_ROCKBOX_DIR: &str = ".rockbox";
 
 #[derive(Parser)]
 #[command(
@@ -37,7 +79,298 @@ enum Commands {
         #[command(subcommand)]
         command: AccountCommand,
     },
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommand,
+    },
+    Device {
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
+    Archive {
+        #[command(subcommand)]
+        command: ArchiveCommand,
+    },
+    Eligibility {
+        #[command(subcommand)]
+        command: EligibilityCommand,
+    },
+    Exclude {
+        #[command(subcommand)]
+        command: ExcludeCommand,
+    },
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
     Scrobble(ScrobbleArgs),
+    Watch(WatchArgs),
+    Tags(TagsArgs),
+}
+
+#[derive(Subcommand)]
+enum QueueCommand {
+    List {
+        #[arg(long, help = "Filter by service")]
+        service: Option<String>,
+        #[arg(long, help = "Filter by username")]
+        username: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Flush {
+        #[arg(long, help = "Limit to one service")]
+        service: Option<String>,
+        #[arg(long, help = "Limit to one username")]
+        username: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Print raw scrobble API responses"
+        )]
+        debug_response: bool,
+    },
+    Drop {
+        #[arg(long, help = "Limit to one service")]
+        service: Option<String>,
+        #[arg(long, help = "Limit to one username")]
+        username: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ArchiveCommand {
+    Replay(ReplayArgs),
+}
+
+#[derive(Subcommand)]
+enum EligibilityCommand {
+    Set {
+        #[command(flatten)]
+        scope: EligibilityScope,
+        #[command(flatten)]
+        rules: EligibilityArgs,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Reset {
+        #[command(flatten)]
+        scope: EligibilityScope,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Show {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExcludeCommand {
+    Add(ExcludeAddArgs),
+    Remove {
+        name: String,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    List {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+}
+
+#[derive(Parser)]
+#[command(group(
+    ArgGroup::new("pattern")
+        .required(true)
+        .multiple(true)
+        .args(["path", "genre", "artist", "album", "comment"])
+))]
+struct ExcludeAddArgs {
+    name: String,
+    #[arg(long, value_name = "GLOB", help = "Path logged by Rockbox")]
+    path: Option<String>,
+    #[arg(long, value_name = "GLOB", help = "Tagcache genre")]
+    genre: Option<String>,
+    #[arg(long, value_name = "GLOB", help = "Tagcache artist")]
+    artist: Option<String>,
+    #[arg(long, value_name = "GLOB", help = "Tagcache album")]
+    album: Option<String>,
+    #[arg(long, value_name = "GLOB", help = "Tagcache comment")]
+    comment: Option<String>,
+    #[arg(
+        long = "account",
+        value_name = "SERVICE:USERNAME",
+        help = "Account the rule applies to; repeat for several (default: all accounts)"
+    )]
+    accounts: Vec<String>,
+    #[arg(long, value_name = "PATH")]
+    config_path: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum RulesCommand {
+    Add(RulesAddArgs),
+    Remove {
+        #[arg(help = "Position of the rule as shown by `rules list`")]
+        position: usize,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    List {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Test(RulesTestArgs),
+}
+
+#[derive(Parser)]
+#[command(group(
+    ArgGroup::new("matcher")
+        .required(true)
+        .args(["exact", "regex"])
+))]
+struct RulesAddArgs {
+    #[arg(long, help = "Field to rewrite: artist, title or album")]
+    field: RewriteField,
+    #[arg(long, help = "Rewrite the field when it equals this value")]
+    exact: Option<String>,
+    #[arg(long, help = "Replace every match of this regex")]
+    regex: Option<String>,
+    #[arg(
+        long = "replace",
+        value_name = "TEXT",
+        help = "Replacement; regex rules may refer to groups as $1"
+    )]
+    replacement: String,
+    #[arg(long, help = "Insert the rule at this position (default: last)")]
+    position: Option<usize>,
+    #[arg(long, value_name = "PATH")]
+    config_path: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct RulesTestArgs {
+    #[arg(long, help = "Device profile to read playback.log from")]
+    device: Option<String>,
+    #[arg(long, help = "Path to the .rockbox directory (default: .rockbox)")]
+    rockbox_dir: Option<PathBuf>,
+    #[arg(long, help = "Optional path to playback.log")]
+    playback_log: Option<PathBuf>,
+    #[arg(long, value_name = "PATH")]
+    config_path: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct EligibilityScope {
+    #[arg(
+        long,
+        conflicts_with = "account",
+        help = "Device profile to change (default: the global rule)"
+    )]
+    device: Option<String>,
+    #[arg(
+        long,
+        value_name = "SERVICE:USERNAME",
+        help = "Account to change (default: the global rule)"
+    )]
+    account: Option<String>,
+}
+
+#[derive(Args)]
+struct EligibilityArgs {
+    #[arg(long, help = "Skip tracks shorter than this")]
+    min_track_seconds: Option<u32>,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "Share of a track that must be played"
+    )]
+    min_played_percent: Option<u8>,
+    #[arg(long, help = "Play time that always counts, however long the track")]
+    max_required_seconds: Option<u32>,
+    #[arg(
+        long,
+        value_name = "BOOL",
+        help = "Count only tracks played to the end (true or false)"
+    )]
+    finished_only: Option<bool>,
+}
+
+impl EligibilityArgs {
+    fn overrides(self) -> EligibilityOverrides {
+        EligibilityOverrides {
+            min_track_seconds: self.min_track_seconds,
+            min_played_percent: self.min_played_percent,
+            max_required_seconds: self.max_required_seconds,
+            finished_only: self.finished_only,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum DeviceCommand {
+    Add(DeviceAddArgs),
+    Remove {
+        name: String,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    List {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Scan {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+}
+
+#[derive(Parser)]
+#[command(group(
+    ArgGroup::new("location")
+        .required(true)
+        .args(["rockbox_dir", "mount_label"])
+))]
+struct DeviceAddArgs {
+    name: String,
+    #[arg(long, help = "Path to the device's .rockbox directory")]
+    rockbox_dir: Option<PathBuf>,
+    #[arg(long, help = "Filesystem label of the device")]
+    mount_label: Option<String>,
+    #[arg(
+        long = "account",
+        value_name = "SERVICE:USERNAME",
+        help = "Account to scrobble to; repeat for several (default: all accounts)"
+    )]
+    accounts: Vec<String>,
+    #[arg(
+        long,
+        help = "Timezone of the player clock, e.g. Europe/Berlin (default: local)"
+    )]
+    timezone: Option<String>,
+    #[arg(
+        long,
+        help = "Stamp scrobbles with the logged time instead of when the listen started"
+    )]
+    logged_timestamps: bool,
+    #[arg(
+        long,
+        help = "Longest pause between log lines that are joined into one listen"
+    )]
+    max_pause_seconds: Option<u32>,
+    #[command(flatten)]
+    eligibility: EligibilityArgs,
+    #[arg(long, value_name = "PATH")]
+    config_path: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -51,6 +384,38 @@ enum ServiceCommand {
         #[arg(long, value_name = "PATH")]
         config_path: Option<PathBuf>,
     },
+    Add {
+        service: String,
+        #[arg(long, help = "Audioscrobbler 2.0 API base URL")]
+        base_url: String,
+        #[arg(long, help = "API key (default: cobblestone)")]
+        api_key: Option<String>,
+        #[arg(long, help = "API secret (default: cobblestone)")]
+        api_secret: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Remove {
+        service: String,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    List {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    Encrypt {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Decrypt {
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -61,6 +426,23 @@ enum AccountCommand {
         username: String,
         #[arg(long, help = "Account password")]
         password: Option<String>,
+        #[arg(long, help = "User token (ListenBrainz)")]
+        token: Option<String>,
+        #[arg(long, help = "API root for self-hosted ListenBrainz instances")]
+        api_root: Option<String>,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Login {
+        service: String,
+        #[arg(long, value_name = "PATH")]
+        config_path: Option<PathBuf>,
+    },
+    Migrate {
+        #[arg(long, help = "Limit to one service")]
+        service: Option<String>,
+        #[arg(long, help = "Limit to one username")]
+        username: Option<String>,
         #[arg(long, value_name = "PATH")]
         config_path: Option<PathBuf>,
     },
@@ -79,14 +461,20 @@ enum AccountCommand {
     },
 }
 
-#[derive(Parser)]
+// Each bool is an independent command-line switch.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Parser, Clone)]
 struct ScrobbleArgs {
+    #[arg(long, help = "Path to the .rockbox directory (default: .rockbox)")]
+    rockbox_dir: Option<PathBuf>,
+    #[arg(long, help = "Device profile to scrobble from")]
+    device: Option<String>,
     #[arg(
         long,
-        default_value = ".rockbox",
-        help = "Path to the .rockbox directory"
+        conflicts_with_all = ["rockbox_dir", "device", "playback_log"],
+        help = "Scrobble every mounted Rockbox player"
     )]
-    rockbox_dir: PathBuf,
+    all_devices: bool,
     #[arg(long, help = "Optional path to playback.log")]
     playback_log: Option<PathBuf>,
     #[arg(long, help = "Limit to one service")]
@@ -102,6 +490,109 @@ struct ScrobbleArgs {
         help = "Do not truncate playback.log after success"
     )]
     truncate: bool,
+    #[arg(
+        long,
+        conflicts_with = "truncate",
+        help = "Archive processed playback.log lines instead of deleting them"
+    )]
+    archive: bool,
+    #[arg(
+        long,
+        help = "Stamp scrobbles with the logged time instead of when the listen started"
+    )]
+    logged_timestamps: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Parse and report without scrobbling"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print raw scrobble API responses"
+    )]
+    debug_response: bool,
+}
+
+// Each bool is an independent command-line switch.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Parser)]
+struct WatchArgs {
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Seconds between checks for mounted players"
+    )]
+    interval: u64,
+    #[arg(
+        long,
+        default_value_t = 300,
+        help = "Seconds between retries of queued scrobbles"
+    )]
+    retry_interval: u64,
+    #[arg(long, help = "Limit to one service")]
+    service: Option<String>,
+    #[arg(long, help = "Limit to one username")]
+    username: Option<String>,
+    #[arg(long, value_name = "PATH")]
+    config_path: Option<PathBuf>,
+    #[arg(
+        long = "no-truncate",
+        action = ArgAction::SetFalse,
+        default_value_t = true,
+        help = "Do not truncate playback.log after success"
+    )]
+    truncate: bool,
+    #[arg(
+        long,
+        conflicts_with = "truncate",
+        help = "Archive processed playback.log lines instead of deleting them"
+    )]
+    archive: bool,
+    #[arg(
+        long,
+        help = "Stamp scrobbles with the logged time instead of when the listen started"
+    )]
+    logged_timestamps: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Print raw scrobble API responses"
+    )]
+    debug_response: bool,
+}
+
+// Each bool is an independent command-line switch.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Parser)]
+struct ReplayArgs {
+    #[arg(long, help = "Service of the account to replay to")]
+    service: String,
+    #[arg(long, help = "Username of the account to replay to")]
+    username: String,
+    #[arg(long, help = "Device profile whose archive to replay")]
+    device: Option<String>,
+    #[arg(long, help = "Path to the .rockbox directory (default: .rockbox)")]
+    rockbox_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "playback.log whose archive to replay (default: the device's)"
+    )]
+    playback_log: Option<PathBuf>,
+    #[arg(long, value_name = "YYYY-MM-DD", help = "First day of plays to replay")]
+    from: Option<NaiveDate>,
+    #[arg(long, value_name = "YYYY-MM-DD", help = "Last day of plays to replay")]
+    to: Option<NaiveDate>,
+    #[arg(
+        long,
+        help = "Stamp scrobbles with the logged time instead of when the listen started"
+    )]
+    logged_timestamps: bool,
+    #[arg(long, help = "Also resubmit plays the account already received")]
+    force: bool,
+    #[arg(long, value_name = "PATH")]
+    config_path: Option<PathBuf>,
     #[arg(
         long,
         default_value_t = false,
@@ -116,6 +607,18 @@ struct ScrobbleArgs {
     debug_response: bool,
 }
 
+#[derive(Parser)]
+struct TagsArgs {
+    #[arg(
+        long,
+        default_value = DEFAULT_ROCKBOX_DIR,
+        help = "Path to the .rockbox directory"
+    )]
+    rockbox_dir: PathBuf,
+    #[arg(help = "Track paths as stored on the device (default: paths in playback.log)")]
+    paths: Vec<String>,
+}
+
 fn main() {
     if let Err(err) = run() {
         eprintln!("{err}");
@@ -125,51 +628,180 @@ fn main() {
 
 fn run() -> Result<()> {
     let cli = Cli::parse();
+    // Mount tables, labels and media directories are looked up below this.
+    let root = Path::new("/");
     match cli.command {
-        Commands::Service { command } => match command {
-            ServiceCommand::SetKeys {
-                service,
-                api_key,
-                api_secret,
-                config_path,
-            } => {
-                if service != "lastfm" {
-                    bail!("Only lastfm supports setting API keys.");
-                }
-                let config_path = config_path.unwrap_or_else(default_config_path);
-                let mut config = load_config(&config_path)?;
-                set_service_keys(&mut config, &service, &api_key, &api_secret);
-                save_config(&config, &config_path)?;
-                println!("Saved API keys for {service} in {}", config_path.display());
-            }
-        },
+        Commands::Service { command } => handle_service(command)?,
         Commands::Account { command } => handle_account(command)?,
-        Commands::Scrobble(args) => handle_scrobble(args)?,
+        Commands::Config { command } => handle_config(command)?,
+        Commands::Queue { command } => handle_queue(command)?,
+        Commands::Device { command } => handle_device(command, root)?,
+        Commands::Archive { command } => handle_archive(command, root)?,
+        Commands::Eligibility { command } => handle_eligibility(command)?,
+        Commands::Exclude { command } => handle_exclude(command)?,
+        Commands::Rules { command } => handle_rules(command, root)?,
+        Commands::Scrobble(args) => handle_scrobble(args, root)?,
+        Commands::Watch(args) => handle_watch(args, root)?,
+        Commands::Tags(args) => handle_tags(args)?,
     }
     Ok(())
 }
 
-fn handle_account(command: AccountCommand) -> Result<()> {
+fn handle_service(command: ServiceCommand) -> Result<()> {
     match command {
-        AccountCommand::Add {
+        ServiceCommand::SetKeys {
             service,
-            username,
+            api_key,
+            api_secret,
+            config_path,
+        } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if !matches!(
+                Service::resolve(&config, &service),
+                Ok(Service::LastFm | Service::Custom { .. })
+            ) {
+                bail!("Only lastfm and custom services support setting API keys.");
+            }
+            set_service_keys(&mut config, &service, &api_key, &api_secret);
+            save_config(&config, &config_path)?;
+            println!("Saved API keys for {service} in {}", config_path.display());
+        }
+        ServiceCommand::Add {
+            service,
+            base_url,
+            api_key,
+            api_secret,
+            config_path,
+        } => {
+            if BUILTIN_SERVICES.contains(&service.as_str()) {
+                bail!("{service} is a built-in service.");
+            }
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            add_custom_service(
+                &mut config,
+                &service,
+                &base_url,
+                api_key.as_deref().unwrap_or(default_custom_api_key()),
+                api_secret.as_deref().unwrap_or(default_custom_api_key()),
+            );
+            save_config(&config, &config_path)?;
+            println!(
+                "Saved service {service} at {base_url} in {}",
+                config_path.display()
+            );
+        }
+        ServiceCommand::Remove {
+            service,
+            config_path,
+        } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if !remove_custom_service(&mut config, &service) {
+                bail!("No custom service named {service}");
+            }
+            save_config(&config, &config_path)?;
+            println!("Removed service {service}");
+        }
+        ServiceCommand::List { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let config = load_config(&config_path)?;
+            for name in BUILTIN_SERVICES {
+                let service = Service::resolve(&config, name)?;
+                println!("{name}\t{}", service.base_url());
+            }
+            let mut custom: Vec<_> = config
+                .services
+                .iter()
+                .filter_map(|(name, keys)| keys.base_url.as_ref().map(|url| (name, url)))
+                .collect();
+            custom.sort();
+            for (name, base_url) in custom {
+                println!("{name}\t{base_url}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_config(command: ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::Encrypt { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if config.passphrase.is_some() {
+                bail!("{} is already encrypted.", config_path.display());
+            }
+            config.passphrase = Some(read_passphrase(true)?);
+            save_config(&config, &config_path)?;
+            println!("Encrypted credentials in {}", config_path.display());
+        }
+        ConfigCommand::Decrypt { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if config.passphrase.take().is_none() {
+                bail!("{} is not encrypted.", config_path.display());
+            }
+            save_config(&config, &config_path)?;
+            println!("Decrypted credentials in {}", config_path.display());
+        }
+    }
+    Ok(())
+}
+
+fn handle_account(command: AccountCommand) -> Result<()> {
+    match command {
+        AccountCommand::Add {
+            service,
+            username,
             password,
+            token,
+            api_root,
             config_path,
         } => {
             let config_path = config_path.unwrap_or_else(default_config_path);
             let mut config = load_config(&config_path)?;
-            let password = match password {
-                Some(value) => value,
-                None => prompt_password_confirm()?,
-            };
-            add_account(&mut config, &service, &username, &password);
+            if Service::resolve(&config, &service)?.uses_token() {
+                if password.is_some() {
+                    bail!("{service} accounts use --token instead of a password.");
+                }
+                let token = match token {
+                    Some(value) => value,
+                    None => rpassword::prompt_password("Token: ")?,
+                };
+                add_token_account(
+                    &mut config,
+                    &service,
+                    &username,
+                    &token,
+                    api_root.as_deref(),
+                );
+            } else {
+                if token.is_some() || api_root.is_some() {
+                    bail!("--token and --api-root only apply to listenbrainz accounts.");
+                }
+                let password = match password {
+                    Some(value) => value,
+                    None => prompt_password_confirm()?,
+                };
+                add_account(&mut config, &service, &username, &password);
+            }
             save_config(&config, &config_path)?;
             println!(
                 "Saved {service} account for {username} in {}",
                 config_path.display()
             );
         }
+        AccountCommand::Login {
+            service,
+            config_path,
+        } => account_login(&service, config_path)?,
+        AccountCommand::Migrate {
+            service,
+            username,
+            config_path,
+        } => account_migrate(service.as_deref(), username.as_deref(), config_path)?,
         AccountCommand::Remove {
             service,
             username,
@@ -194,125 +826,1477 @@ fn handle_account(command: AccountCommand) -> Result<()> {
                 bail!("No accounts configured.");
             }
             for account in accounts {
-                println!("{}\t{}", account.service, account.username);
+                let auth = if account.token.is_some() {
+                    "token"
+                } else if account.session_key.is_some() {
+                    "session"
+                } else {
+                    "no session"
+                };
+                println!("{}\t{}\t{auth}", account.service, account.username);
             }
         }
     }
     Ok(())
 }
 
-fn handle_scrobble(args: ScrobbleArgs) -> Result<()> {
-    let config_path = args.config_path.unwrap_or_else(default_config_path);
-    let config = load_config(&config_path)?;
-    let accounts: Vec<_> = iter_accounts(&config, args.service.as_deref())
-        .filter(|account| {
-            args.username
-                .as_deref()
-                .is_none_or(|user| account.username == user)
-        })
+fn handle_queue(command: QueueCommand) -> Result<()> {
+    match command {
+        QueueCommand::List {
+            service,
+            username,
+            config_path,
+        } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let queue = load_queue(&queue_path(&config_path))?;
+            let entries: Vec<_> = queue
+                .entries
+                .iter()
+                .filter(|entry| {
+                    service.as_deref().is_none_or(|svc| svc == entry.service)
+                        && username
+                            .as_deref()
+                            .is_none_or(|user| user == entry.username)
+                })
+                .collect();
+            if entries.is_empty() {
+                println!("No queued scrobbles.");
+                return Ok(());
+            }
+            for entry in entries {
+                let played_at = chrono::DateTime::from_timestamp(entry.track.timestamp, 0)
+                    .map_or_else(
+                        || entry.track.timestamp.to_string(),
+                        |time| time.with_timezone(&chrono::Local).to_rfc3339(),
+                    );
+                println!(
+                    "{}\t{}\t{}\t{} - {}\t{}",
+                    entry.service,
+                    entry.username,
+                    played_at,
+                    entry.track.artist,
+                    entry.track.title,
+                    entry.error
+                );
+            }
+        }
+        QueueCommand::Flush {
+            service,
+            username,
+            config_path,
+            debug_response,
+        } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            let remaining = retry_queue(
+                &mut config,
+                &config_path,
+                service.as_deref(),
+                username.as_deref(),
+                debug_response,
+            )?;
+            if remaining > 0 {
+                println!("{remaining} scrobbles remain queued.");
+            }
+        }
+        QueueCommand::Drop {
+            service,
+            username,
+            config_path,
+        } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let queue_path = queue_path(&config_path);
+            let mut queue = load_queue(&queue_path)?;
+            let dropped = queue.drop_matching(service.as_deref(), username.as_deref());
+            save_queue(&queue, &queue_path)?;
+            println!("Dropped {dropped} queued scrobbles.");
+        }
+    }
+    Ok(())
+}
+
+fn select_accounts(
+    config: &config::Config,
+    service: Option<&str>,
+    username: Option<&str>,
+) -> Result<Vec<config::Account>> {
+    let accounts: Vec<_> = iter_accounts(config, service)
+        .filter(|account| username.is_none_or(|user| account.username == user))
         .cloned()
         .collect();
     if accounts.is_empty() {
         bail!("No matching accounts configured.");
     }
+    Ok(accounts)
+}
 
-    let playback_path = args
-        .playback_log
-        .unwrap_or_else(|| args.rockbox_dir.join("playback.log"));
-    if !playback_path.exists() {
-        bail!("Missing playback log at {}", playback_path.display());
+fn account_login(service: &str, config_path: Option<PathBuf>) -> Result<()> {
+    let config_path = config_path.unwrap_or_else(default_config_path);
+    let mut config = load_config(&config_path)?;
+    let resolved = Service::resolve(&config, service)?;
+    if resolved.uses_token() {
+        bail!("{service} accounts use `account add --token` instead.");
     }
+    let Some(keys) = resolved.keys(&config, service) else {
+        bail!("Missing API keys for {service}; run `service set-keys` first.");
+    };
+    let token = request_auth_token(&resolved, &keys)?;
+    println!("Open this URL in a browser and allow access:");
+    println!("  {}", resolved.auth_url(&keys.api_key, &token));
+    println!("Press Enter once access has been granted.");
+    std::io::stdin().read_line(&mut String::new())?;
+    let (username, session_key) = fetch_web_session(&resolved, &keys, &token)?;
+    add_session_account(&mut config, service, &username, &session_key);
+    save_config(&config, &config_path)?;
+    println!(
+        "Saved {service} session for {username} in {}",
+        config_path.display()
+    );
+    Ok(())
+}
 
-    let entries = parse_playback_log(&playback_path)?;
-    if entries.is_empty() {
+fn account_migrate(
+    service: Option<&str>,
+    username: Option<&str>,
+    config_path: Option<PathBuf>,
+) -> Result<()> {
+    let config_path = config_path.unwrap_or_else(default_config_path);
+    let mut config = load_config(&config_path)?;
+    let accounts: Vec<_> = select_accounts(&config, service, username)?
+        .into_iter()
+        .filter(|account| account.password_md5.is_some())
+        .collect();
+    if accounts.is_empty() {
+        println!("No password-based accounts to migrate.");
+        return Ok(());
+    }
+    for account in accounts {
+        let resolved = Service::resolve(&config, &account.service)?;
+        let Some(keys) = resolved.keys(&config, &account.service) else {
+            println!("Missing API keys for {}", account.service);
+            continue;
+        };
+        match fetch_password_session(&resolved, &keys, &account) {
+            Ok(session_key) => {
+                add_session_account(
+                    &mut config,
+                    &account.service,
+                    &account.username,
+                    &session_key,
+                );
+                println!(
+                    "Migrated {} account for {} to a session key",
+                    account.service, account.username
+                );
+            }
+            Err(err) => println!(
+                "Failed migrating {} account for {}: {err}",
+                account.service, account.username
+            ),
+        }
+    }
+    save_config(&config, &config_path)?;
+    Ok(())
+}
+
+fn handle_device(command: DeviceCommand, root: &Path) -> Result<()> {
+    match command {
+        DeviceCommand::Add(args) => {
+            let config_path = args.config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            for id in &args.accounts {
+                if !config.accounts.iter().any(|account| account.id() == *id) {
+                    bail!("No account {id} configured; use SERVICE:USERNAME.");
+                }
+            }
+            if let Some(timezone) = &args.timezone {
+                parse_timezone(timezone)?;
+            }
+            let rockbox_dir = args.rockbox_dir.map(std::path::absolute).transpose()?;
+            add_device(
+                &mut config,
+                Device {
+                    name: args.name.clone(),
+                    rockbox_dir,
+                    mount_label: args.mount_label,
+                    accounts: args.accounts,
+                    timezone: args.timezone,
+                    logged_timestamps: args.logged_timestamps,
+                    max_pause_seconds: args.max_pause_seconds,
+                    eligibility: args.eligibility.overrides(),
+                },
+            );
+            save_config(&config, &config_path)?;
+            println!("Saved device {} in {}", args.name, config_path.display());
+        }
+        DeviceCommand::Remove { name, config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if !remove_device(&mut config, &name) {
+                bail!("No device named {name}");
+            }
+            save_config(&config, &config_path)?;
+            println!("Removed device {name}");
+        }
+        DeviceCommand::List { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let config = load_config(&config_path)?;
+            if config.devices.is_empty() {
+                bail!("No devices configured.");
+            }
+            for device in &config.devices {
+                let location = match (&device.rockbox_dir, &device.mount_label) {
+                    (Some(dir), _) => dir.display().to_string(),
+                    (None, Some(label)) => format!("label {label}"),
+                    (None, None) => "-".to_string(),
+                };
+                let accounts = if device.accounts.is_empty() {
+                    "all accounts".to_string()
+                } else {
+                    device.accounts.join(",")
+                };
+                println!(
+                    "{}\t{location}\t{accounts}\t{}",
+                    device.name,
+                    device.timezone.as_deref().unwrap_or("local time")
+                );
+            }
+        }
+        DeviceCommand::Scan { config_path } => scan_devices(config_path, root)?,
+    }
+    Ok(())
+}
+
+fn handle_eligibility(command: EligibilityCommand) -> Result<()> {
+    match command {
+        EligibilityCommand::Set {
+            scope,
+            rules,
+            config_path,
+        } => {
+            let rules = rules.overrides();
+            if rules.is_empty() {
+                bail!("Pass at least one eligibility option to set.");
+            }
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            scope_overrides(&mut config, &scope)?.merge(&rules);
+            save_config(&config, &config_path)?;
+            println!(
+                "{}: {}",
+                scope_name(&scope),
+                scope_policy(&config, &scope)?.describe()
+            );
+        }
+        EligibilityCommand::Reset { scope, config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            *scope_overrides(&mut config, &scope)? = EligibilityOverrides::default();
+            save_config(&config, &config_path)?;
+            println!("Reset eligibility for {}", scope_name(&scope));
+        }
+        EligibilityCommand::Show { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let config = load_config(&config_path)?;
+            let global = EligibilityPolicy::default().with_overrides(&config.eligibility);
+            println!("global\t{}", global.describe());
+            for device in config.devices.iter().filter(|d| !d.eligibility.is_empty()) {
+                let policy = global.clone().with_overrides(&device.eligibility);
+                println!("device {}\t{}", device.name, policy.describe());
+            }
+            for account in config.accounts.iter().filter(|a| !a.eligibility.is_empty()) {
+                let policy = global.clone().with_overrides(&account.eligibility);
+                println!("account {}\t{}", account.id(), policy.describe());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_exclude(command: ExcludeCommand) -> Result<()> {
+    match command {
+        ExcludeCommand::Add(args) => {
+            let config_path = args.config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            for id in &args.accounts {
+                if !config.accounts.iter().any(|account| account.id() == *id) {
+                    bail!("No account {id} configured; use SERVICE:USERNAME.");
+                }
+            }
+            let rule = ExclusionRule {
+                name: args.name.clone(),
+                path: args.path,
+                genre: args.genre,
+                artist: args.artist,
+                album: args.album,
+                comment: args.comment,
+                accounts: args.accounts,
+            };
+            Exclusions::new(std::slice::from_ref(&rule))?;
+            add_exclusion(&mut config, rule);
+            save_config(&config, &config_path)?;
+            println!(
+                "Saved exclusion rule {} in {}",
+                args.name,
+                config_path.display()
+            );
+        }
+        ExcludeCommand::Remove { name, config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if !remove_exclusion(&mut config, &name) {
+                bail!("No exclusion rule named {name}");
+            }
+            save_config(&config, &config_path)?;
+            println!("Removed exclusion rule {name}");
+        }
+        ExcludeCommand::List { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let config = load_config(&config_path)?;
+            if config.exclusions.is_empty() {
+                bail!("No exclusion rules configured.");
+            }
+            for rule in &config.exclusions {
+                let patterns: Vec<_> = [
+                    ("path", &rule.path),
+                    ("genre", &rule.genre),
+                    ("artist", &rule.artist),
+                    ("album", &rule.album),
+                    ("comment", &rule.comment),
+                ]
+                .into_iter()
+                .filter_map(|(field, pattern)| {
+                    pattern.as_ref().map(|pattern| format!("{field}={pattern}"))
+                })
+                .collect();
+                let accounts = if rule.accounts.is_empty() {
+                    "all accounts".to_string()
+                } else {
+                    rule.accounts.join(",")
+                };
+                println!("{}\t{}\t{accounts}", rule.name, patterns.join(" "));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_rules(command: RulesCommand, root: &Path) -> Result<()> {
+    match command {
+        RulesCommand::Add(args) => {
+            let config_path = args.config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            let rule = RewriteRule {
+                field: args.field,
+                exact: args.exact,
+                regex: args.regex,
+                replacement: args.replacement,
+            };
+            let position = args
+                .position
+                .unwrap_or(config.rewrites.len() + 1)
+                .clamp(1, config.rewrites.len() + 1);
+            config.rewrites.insert(position - 1, rule);
+            Rewrites::new(&config.rewrites)?;
+            save_config(&config, &config_path)?;
+            println!("Saved rewrite rule {position} in {}", config_path.display());
+        }
+        RulesCommand::Remove {
+            position,
+            config_path,
+        } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let mut config = load_config(&config_path)?;
+            if position == 0 || position > config.rewrites.len() {
+                bail!("No rewrite rule at position {position}");
+            }
+            config.rewrites.remove(position - 1);
+            save_config(&config, &config_path)?;
+            println!("Removed rewrite rule {position}");
+        }
+        RulesCommand::List { config_path } => {
+            let config_path = config_path.unwrap_or_else(default_config_path);
+            let config = load_config(&config_path)?;
+            if config.rewrites.is_empty() {
+                bail!("No rewrite rules configured.");
+            }
+            for (index, rule) in config.rewrites.iter().enumerate() {
+                let matcher = match (&rule.exact, &rule.regex) {
+                    (Some(exact), _) => format!("exact \"{exact}\""),
+                    (None, Some(regex)) => format!("regex \"{regex}\""),
+                    (None, None) => "-".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{matcher}\t\"{}\"",
+                    index + 1,
+                    rule.field.as_str(),
+                    rule.replacement
+                );
+            }
+        }
+        RulesCommand::Test(args) => test_rewrites(args, root)?,
+    }
+    Ok(())
+}
+
+// Shows what the rewrite rules do to the tracks in the current playback.log,
+// without reading the spool or changing anything.
+fn test_rewrites(args: RulesTestArgs, root: &Path) -> Result<()> {
+    let config_path = args.config_path.unwrap_or_else(default_config_path);
+    let config = load_config(&config_path)?;
+    if config.rewrites.is_empty() {
+        bail!("No rewrite rules configured.");
+    }
+    let rewrites = Rewrites::new(&config.rewrites)?;
+    let profile = resolve_profile(
+        &config,
+        args.device.as_deref(),
+        args.rockbox_dir.as_deref(),
+        false,
+        root,
+    )?;
+    let (rockbox_info, tagcache_format, playback_path) =
+        inspect_player(&profile.rockbox_dir, args.playback_log)?;
+    let entries = parse_playback_log(&playback_path, profile.timezone)?;
+    let candidates = candidates_from_entries(
+        &profile,
+        tagcache_format,
+        rockbox_info.as_ref(),
+        &entries,
+        &[],
+    )?;
+    let mut seen = HashSet::new();
+    let mut rewritten = 0;
+    for candidate in &candidates {
+        if !seen.insert(candidate.track.path.as_str()) {
+            continue;
+        }
+        let before = &candidate.track;
+        let mut after = before.clone();
+        rewrites.apply(&mut after);
+        let changes: Vec<_> = [
+            ("artist", Some(&before.artist), Some(&after.artist)),
+            ("title", Some(&before.title), Some(&after.title)),
+            ("album", before.album.as_ref(), after.album.as_ref()),
+        ]
+        .into_iter()
+        .filter(|(_, before, after)| before != after)
+        .collect();
+        if changes.is_empty() {
+            continue;
+        }
+        rewritten += 1;
+        println!("{}", before.path);
+        for (field, before, after) in changes {
+            println!(
+                "  {field}: {} -> {}",
+                before.map_or("-", String::as_str),
+                after.map_or("-", String::as_str)
+            );
+        }
+    }
+    println!("{rewritten} of {} tracks rewritten.", seen.len());
+    Ok(())
+}
+
+fn scope_overrides<'a>(
+    config: &'a mut config::Config,
+    scope: &EligibilityScope,
+) -> Result<&'a mut EligibilityOverrides> {
+    Ok(match (&scope.device, &scope.account) {
+        (Some(name), _) => &mut find_device_mut(config, name)?.eligibility,
+        (None, Some(id)) => &mut find_account_mut(config, id)?.eligibility,
+        (None, None) => &mut config.eligibility,
+    })
+}
+
+fn scope_name(scope: &EligibilityScope) -> String {
+    match (&scope.device, &scope.account) {
+        (Some(name), _) => format!("device {name}"),
+        (None, Some(id)) => format!("account {id}"),
+        (None, None) => "global".to_string(),
+    }
+}
+
+// The rule a scope ends up with on top of the global one. When scrobbling, an
+// account's overrides apply on top of the device's.
+fn scope_policy(config: &config::Config, scope: &EligibilityScope) -> Result<EligibilityPolicy> {
+    let policy = EligibilityPolicy::default().with_overrides(&config.eligibility);
+    Ok(match (&scope.device, &scope.account) {
+        (Some(name), _) => policy.with_overrides(&find_device(config, name)?.eligibility),
+        (None, Some(id)) => {
+            let account = config
+                .accounts
+                .iter()
+                .find(|account| account.id() == *id)
+                .with_context(|| format!("No account {id} configured"))?;
+            policy.with_overrides(&account.eligibility)
+        }
+        (None, None) => policy,
+    })
+}
+
+fn scan_devices(config_path: Option<PathBuf>, root: &Path) -> Result<()> {
+    let config_path = config_path.unwrap_or_else(default_config_path);
+    let config = load_config(&config_path)?;
+    let players = discover_players(root)?;
+    if players.is_empty() {
+        bail!("No mounted Rockbox players found.");
+    }
+    for player in &players {
+        let log = if player.has_playback_log {
+            "playback.log"
+        } else {
+            "no playback.log"
+        };
+        let profile = matching_device(root, &config, player).map_or("-", |device| &device.name);
+        println!(
+            "{}\t{}\t{log}\t{profile}",
+            player.mount_point.display(),
+            player.describe()
+        );
+    }
+    Ok(())
+}
+
+fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse()
+        .map_err(|_| anyhow::anyhow!("Unknown timezone {name}"))
+}
+
+fn device_accounts(
+    device: &Device,
+    accounts: Vec<config::Account>,
+) -> Result<Vec<config::Account>> {
+    if device.accounts.is_empty() {
+        return Ok(accounts);
+    }
+    let accounts: Vec<_> = accounts
+        .into_iter()
+        .filter(|account| device.accounts.contains(&account.id()))
+        .collect();
+    if accounts.is_empty() {
+        bail!("No matching accounts configured for device {}", device.name);
+    }
+    Ok(accounts)
+}
+
+fn handle_scrobble(args: ScrobbleArgs, root: &Path) -> Result<()> {
+    let config_path = args.config_path.clone().unwrap_or_else(default_config_path);
+    let mut config = load_config(&config_path)?;
+    if !args.all_devices {
+        return scrobble_player(args, &mut config, &config_path, root);
+    }
+    let players = discover_players(root)?;
+    if players.is_empty() {
+        bail!("No mounted Rockbox players found.");
+    }
+    let mut failed = 0;
+    for player in players {
+        if !player.has_playback_log {
+            println!("Skipping {}: no playback.log", player.mount_point.display());
+            continue;
+        }
+        if let Err(err) = scrobble_discovered(&args, &mut config, &config_path, player, root) {
+            println!("{err}");
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("Scrobbling failed for {failed} players.");
+    }
+    Ok(())
+}
+
+fn scrobble_discovered(
+    args: &ScrobbleArgs,
+    config: &mut config::Config,
+    config_path: &Path,
+    player: Player,
+    root: &Path,
+) -> Result<()> {
+    println!("Scrobbling player at {}", player.mount_point.display());
+    let mut player_args = args.clone();
+    player_args.all_devices = false;
+    player_args.device = matching_device(root, config, &player).map(|device| device.name.clone());
+    player_args.rockbox_dir = Some(player.rockbox_dir);
+    scrobble_player(player_args, config, config_path, root)
+}
+
+fn handle_watch(args: WatchArgs, root: &Path) -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+    // The first signal lets the current run finish; a second one ends it
+    // before the next request, queueing what was not submitted.
+    ctrlc::set_handler(move || {
+        if handler_stop.swap(true, Ordering::SeqCst) {
+            interrupt_submissions();
+            println!("Stopping after the current request...");
+            return;
+        }
+        println!("Stopping after the current run...");
+    })
+    .context("Failed installing signal handler")?;
+
+    let config_path = args.config_path.unwrap_or_else(default_config_path);
+    let mut config = load_config(&config_path)?;
+    let scrobble_args = ScrobbleArgs {
+        rockbox_dir: None,
+        device: None,
+        all_devices: false,
+        playback_log: None,
+        service: args.service,
+        username: args.username,
+        config_path: Some(config_path.clone()),
+        truncate: args.truncate,
+        archive: args.archive,
+        logged_timestamps: args.logged_timestamps,
+        dry_run: false,
+        debug_response: args.debug_response,
+    };
+    let poll_interval = Duration::from_secs(args.interval);
+    let retry_interval = Duration::from_secs(args.retry_interval);
+    let mut next_retry = Instant::now() + retry_interval;
+    // Players already handled; one is scrobbled again after it was unmounted.
+    let mut seen: Vec<PathBuf> = Vec::new();
+    println!("Watching for Rockbox players; press Ctrl-C to stop.");
+    while !stop.load(Ordering::SeqCst) {
+        let players = discover_players(root).unwrap_or_else(|err| {
+            println!("{err}");
+            Vec::new()
+        });
+        seen.retain(|rockbox_dir| {
+            players
+                .iter()
+                .any(|player| player.rockbox_dir == *rockbox_dir)
+        });
+        for player in players {
+            if stop.load(Ordering::SeqCst) || seen.contains(&player.rockbox_dir) {
+                continue;
+            }
+            seen.push(player.rockbox_dir.clone());
+            if !player.has_playback_log {
+                println!(
+                    "Found player at {} without playback.log",
+                    player.mount_point.display()
+                );
+                continue;
+            }
+            if let Err(err) =
+                scrobble_discovered(&scrobble_args, &mut config, &config_path, player, root)
+            {
+                println!("{err}");
+            }
+        }
+        if Instant::now() >= next_retry && !stop.load(Ordering::SeqCst) {
+            if let Err(err) = retry_queue(
+                &mut config,
+                &config_path,
+                scrobble_args.service.as_deref(),
+                scrobble_args.username.as_deref(),
+                scrobble_args.debug_response,
+            ) {
+                println!("{err}");
+            }
+            next_retry = Instant::now() + retry_interval;
+        }
+        sleep_unless_stopped(&stop, poll_interval);
+    }
+    println!("Stopped watching.");
+    Ok(())
+}
+
+fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::SeqCst) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return;
+        }
+        std::thread::sleep(remaining.min(Duration::from_millis(200)));
+    }
+}
+
+fn save_session_keys(
+    config: &mut config::Config,
+    config_path: &Path,
+    accounts: &[config::Account],
+) -> Result<()> {
+    if update_session_keys(config, accounts) {
+        save_config(config, config_path)?;
+    }
+    Ok(())
+}
+
+fn retry_queue(
+    config: &mut config::Config,
+    config_path: &Path,
+    service: Option<&str>,
+    username: Option<&str>,
+    debug_response: bool,
+) -> Result<usize> {
+    let queue_path = queue_path(config_path);
+    let mut queue = load_queue(&queue_path)?;
+    if queue.entries.is_empty() {
+        return Ok(0);
+    }
+    let mut accounts = select_accounts(config, service, username)?;
+    let remaining = flush_queue(config, &mut accounts, &mut queue, debug_response);
+    save_queue(&queue, &queue_path)?;
+    save_session_keys(config, config_path, &accounts)?;
+    Ok(remaining)
+}
+
+// A scrobble run's player and parsing rules once the device profile and
+// command-line options are applied.
+struct RunProfile {
+    device: Option<Device>,
+    rockbox_dir: PathBuf,
+    timezone: Option<Tz>,
+    policy: EligibilityPolicy,
+    timestamps: TimestampMode,
+    max_pause_seconds: i64,
+}
+
+fn resolve_profile(
+    config: &config::Config,
+    device: Option<&str>,
+    rockbox_dir: Option<&Path>,
+    logged_timestamps: bool,
+    root: &Path,
+) -> Result<RunProfile> {
+    let device = device
+        .map(|name| find_device(config, name).cloned())
+        .transpose()?;
+    let rockbox_dir = match (rockbox_dir, &device) {
+        (Some(rockbox_dir), _) => rockbox_dir.to_path_buf(),
+        (None, Some(device)) => device_rockbox_dir(root, device)?,
+        (None, None) => PathBuf::from(DEFAULT_ROCKBOX_DIR),
+    };
+    let mut timezone = None;
+    let mut policy = EligibilityPolicy::default().with_overrides(&config.eligibility);
+    if let Some(device) = &device {
+        timezone = device.timezone.as_deref().map(parse_timezone).transpose()?;
+        policy = policy.with_overrides(&device.eligibility);
+    }
+    let timestamps = TimestampMode::from_logged(
+        logged_timestamps
+            || device
+                .as_ref()
+                .is_some_and(|device| device.logged_timestamps),
+    );
+    let max_pause_seconds = device
+        .as_ref()
+        .and_then(|device| device.max_pause_seconds)
+        .or(config.max_pause_seconds)
+        .map_or(MAX_PAUSE_SECONDS, i64::from);
+    Ok(RunProfile {
+        device,
+        rockbox_dir,
+        timezone,
+        policy,
+        timestamps,
+        max_pause_seconds,
+    })
+}
+
+fn scrobble_player(
+    args: ScrobbleArgs,
+    config: &mut config::Config,
+    config_path: &Path,
+    root: &Path,
+) -> Result<()> {
+    let profile = resolve_profile(
+        config,
+        args.device.as_deref(),
+        args.rockbox_dir.as_deref(),
+        args.logged_timestamps,
+        root,
+    )?;
+    let mut accounts = select_accounts(config, args.service.as_deref(), args.username.as_deref())?;
+    if let Some(device) = &profile.device {
+        accounts = device_accounts(device, accounts)?;
+    }
+
+    let queue_path = queue_path(config_path);
+    let mut queue = retry_queue_first(config, config_path, &mut accounts, &args)?;
+
+    let (rockbox_info, tagcache_format, playback_path) =
+        inspect_player(&profile.rockbox_dir, args.playback_log)?;
+    let log_key = profile
+        .device
+        .as_ref()
+        .map_or_else(|| log_id(&playback_path), |device| device.name.clone());
+    let spool_path = spool_path(config_path, &log_key);
+    let source = read_playback_entries(
+        &playback_path,
+        &spool_path,
+        load_checkpoints(&checkpoints_path(config_path))?.get(&log_key),
+        profile.timezone,
+        args.truncate,
+        args.dry_run,
+    )?;
+    if source.entries.is_empty() {
+        if !args.dry_run {
+            commit_checkpoint(config_path, &log_key, source.checkpoint)?;
+        }
         bail!("No playback entries found.");
     }
 
-    let mut tagcache = TagCache::new(&args.rockbox_dir)?;
-    let (tracks, missing) = build_scrobble_tracks(&entries, &mut tagcache)?;
-    tagcache.close();
+    let candidates = candidates_from_entries(
+        &profile,
+        tagcache_format,
+        rockbox_info.as_ref(),
+        &source.entries,
+        &accounts,
+    )?;
+    let account_tracks = tracks_per_account(
+        config,
+        &candidates,
+        &profile.policy,
+        &accounts,
+        args.dry_run,
+    )?;
+    if account_tracks.iter().all(Vec::is_empty) {
+        // Nothing in the spool will ever be delivered, so it is not kept.
+        if !args.dry_run {
+            if args.truncate {
+                finish_spool(config_path, &log_key, &spool_path, args.archive)?;
+            }
+            commit_checkpoint(config_path, &log_key, source.checkpoint)?;
+        }
+        bail!("No scrobble-eligible tracks found.");
+    }
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let ledger_path = ledger_path(config_path);
+    let mut ledger = load_ledger(&ledger_path)?;
+    let log = log_id(&source.log_path);
+    let failures = scrobble_for_accounts(
+        config,
+        &mut accounts,
+        &account_tracks,
+        &mut ledger,
+        &log,
+        &mut queue,
+        args.debug_response,
+    );
+    save_queue(&queue, &queue_path)?;
+    save_session_keys(config, config_path, &accounts)?;
+    if failures > 0 {
+        println!(
+            "Finished with {failures} scrobble failures; queued in {}",
+            queue_path.display()
+        );
+    }
+    if args.truncate {
+        finish_spool(config_path, &log_key, &spool_path, args.archive)?;
+        let archive = args.archive.then(|| archive_ledger_id(&log_key));
+        ledger.release(&log, archive.as_deref());
+    } else {
+        ledger.retain(&log, &account_tracks.concat());
+    }
+    save_ledger(&ledger, &ledger_path)?;
+    commit_checkpoint(config_path, &log_key, source.checkpoint)
+}
 
+// Stored once the entries are handled. A spooled log is read from the start of
+// the spool, so a checkpoint left by a run that kept the log is dropped.
+fn commit_checkpoint(
+    config_path: &Path,
+    log_key: &str,
+    checkpoint: Option<Checkpoint>,
+) -> Result<()> {
+    let path = checkpoints_path(config_path);
+    let mut checkpoints = load_checkpoints(&path)?;
+    match checkpoint {
+        Some(checkpoint) => checkpoints.set(log_key, checkpoint),
+        None if checkpoints.remove(log_key) => {}
+        None => return Ok(()),
+    }
+    save_checkpoints(&checkpoints, &path)
+}
+
+// Queued plays are older than the log's, so they go first.
+fn retry_queue_first(
+    config: &mut config::Config,
+    config_path: &Path,
+    accounts: &mut [config::Account],
+    args: &ScrobbleArgs,
+) -> Result<Queue> {
+    let queue_path = queue_path(config_path);
+    let mut queue = load_queue(&queue_path)?;
+    if args.dry_run {
+        if !queue.entries.is_empty() {
+            println!("Would retry {} queued scrobbles.", queue.entries.len());
+        }
+    } else {
+        flush_queue(config, accounts, &mut queue, args.debug_response);
+        save_queue(&queue, &queue_path)?;
+        save_session_keys(config, config_path, accounts)?;
+    }
+    Ok(queue)
+}
+
+fn finish_spool(config_path: &Path, log_key: &str, spool_path: &Path, archive: bool) -> Result<()> {
+    if archive {
+        archive_spool(config_path, log_key, spool_path)?;
+    }
+    clear_spool(spool_path)
+}
+
+fn archive_spool(config_path: &Path, log_key: &str, spool_path: &Path) -> Result<()> {
+    let spooled = std::fs::read_to_string(spool_path)
+        .with_context(|| format!("Failed reading spool {}", spool_path.display()))?;
+    let dir = archive_dir(config_path, log_key);
+    let archived = archive_lines(&dir, Local::now().date_naive(), &spooled)?;
+    if !archived.is_empty() {
+        println!("Archived processed entries to {}", dir.display());
+    }
+    Ok(())
+}
+
+// Missing metadata is reported for plays that one of `accounts` would
+// receive, or that the run's policy accepts when no accounts are given.
+fn candidates_from_entries(
+    profile: &RunProfile,
+    tagcache_format: Option<&'static TagcacheFormat>,
+    rockbox_info: Option<&RockboxInfo>,
+    entries: &[PlaybackEntry],
+    accounts: &[config::Account],
+) -> Result<Vec<Candidate>> {
+    let mut policies: Vec<_> = accounts
+        .iter()
+        .map(|account| profile.policy.clone().with_overrides(&account.eligibility))
+        .collect();
+    if policies.is_empty() {
+        policies.push(profile.policy.clone());
+    }
+    let mut tagcache = TagCache::new(&profile.rockbox_dir, tagcache_format)?;
+    let (candidates, missing) = build_scrobble_tracks(
+        entries,
+        &mut tagcache,
+        rockbox_info,
+        profile.timestamps,
+        profile.max_pause_seconds,
+        &policies,
+    )?;
+    tagcache.close();
     if !missing.is_empty() {
         println!("Missing metadata for {} paths", missing.len());
     }
+    Ok(candidates)
+}
+
+fn tracks_per_account(
+    config: &config::Config,
+    candidates: &[Candidate],
+    policy: &EligibilityPolicy,
+    accounts: &[config::Account],
+    dry_run: bool,
+) -> Result<Vec<Vec<ScrobbleTrack>>> {
+    let exclusions = Exclusions::new(&config.exclusions)?;
+    let rewrites = Rewrites::new(&config.rewrites)?;
+    Ok(accounts
+        .iter()
+        .map(|account| {
+            let (tracks, skipped) =
+                account_eligible(candidates, policy, &exclusions, &rewrites, account);
+            if dry_run {
+                report_eligibility("scrobble", account, &tracks, &skipped);
+            } else {
+                report_exclusions(account, &skipped);
+            }
+            tracks
+        })
+        .collect())
+}
+
+// Applies the account's own eligibility overrides on top of the run's policy,
+// then rewrites the tracks the account receives.
+fn account_eligible<'a>(
+    candidates: &'a [Candidate],
+    policy: &EligibilityPolicy,
+    exclusions: &Exclusions,
+    rewrites: &Rewrites,
+    account: &config::Account,
+) -> (Vec<ScrobbleTrack>, Vec<(&'a Candidate, SkipReason)>) {
+    let policy = policy.clone().with_overrides(&account.eligibility);
+    let (mut tracks, skipped) = select_eligible(candidates, &policy, exclusions, &account.id());
+    for track in &mut tracks {
+        rewrites.apply(track);
+    }
+    (tracks, skipped)
+}
+
+fn report_exclusions(account: &config::Account, skipped: &[(&Candidate, SkipReason)]) {
+    let excluded: Vec<_> = skipped
+        .iter()
+        .filter_map(|(candidate, reason)| match reason {
+            SkipReason::Excluded(rule) => Some((candidate, rule)),
+            _ => None,
+        })
+        .collect();
+    if excluded.is_empty() {
+        return;
+    }
+    println!(
+        "Excluded {} plays from {} for {}:",
+        excluded.len(),
+        account.service,
+        account.username
+    );
+    for (candidate, rule) in excluded {
+        println!(
+            "  {} - {}: rule {rule}",
+            candidate.track.artist, candidate.track.title
+        );
+    }
+}
+
+fn report_eligibility(
+    action: &str,
+    account: &config::Account,
+    tracks: &[ScrobbleTrack],
+    skipped: &[(&Candidate, SkipReason)],
+) {
+    println!(
+        "Would {action} {} tracks to {} for {}, skipping {}.",
+        tracks.len(),
+        account.service,
+        account.username,
+        skipped.len()
+    );
+    for (candidate, reason) in skipped {
+        println!(
+            "  {} - {}: {reason}",
+            candidate.track.artist, candidate.track.title
+        );
+    }
+}
+
+struct PlaybackSource {
+    entries: Vec<PlaybackEntry>,
+    log_path: PathBuf,
+    // Where the next --no-truncate run should resume reading.
+    checkpoint: Option<Checkpoint>,
+}
+
+// Truncating runs move the device log into the spool first and read from
+// there, leaving out what a run that kept the log already read; dry runs
+// preview the same merge without touching either file. Runs that keep the log
+// read it in place from the last checkpoint.
+fn read_playback_entries(
+    playback_path: &Path,
+    spool_path: &Path,
+    checkpoint: Option<&Checkpoint>,
+    timezone: Option<Tz>,
+    truncate: bool,
+    dry_run: bool,
+) -> Result<PlaybackSource> {
+    if !truncate {
+        let tail = read_log_tail(playback_path, checkpoint)?;
+        report_restart(playback_path, &tail.resume);
+        return Ok(PlaybackSource {
+            entries: parse_playback_lines(&tail.text, timezone),
+            log_path: playback_path.to_path_buf(),
+            checkpoint: Some(tail.checkpoint),
+        });
+    }
+    let resume = resume_log(playback_path, checkpoint)?;
+    report_restart(playback_path, &resume);
+    let skip = match resume {
+        Resume::Offset(offset) => offset,
+        Resume::Start | Resume::Restart(_) => 0,
+    };
+    let entries = if dry_run {
+        let (merged, _) = merge_playback_log(playback_path, spool_path, skip)?;
+        parse_playback_lines(&merged, timezone)
+    } else {
+        let moved = spool_playback_log(playback_path, spool_path, skip)?;
+        println!(
+            "Moved {moved} entries from {} to {}",
+            playback_path.display(),
+            spool_path.display()
+        );
+        parse_playback_log(spool_path, timezone)?
+    };
+    Ok(PlaybackSource {
+        entries,
+        log_path: spool_path.to_path_buf(),
+        checkpoint: None,
+    })
+}
+
+fn report_restart(playback_path: &Path, resume: &Resume) {
+    if let Resume::Restart(reason) = resume {
+        println!(
+            "{} {reason} since the last run; reading it from the start",
+            playback_path.display()
+        );
+    }
+}
+
+fn inspect_player(
+    rockbox_dir: &Path,
+    playback_log: Option<PathBuf>,
+) -> Result<(
+    Option<RockboxInfo>,
+    Option<&'static TagcacheFormat>,
+    PathBuf,
+)> {
+    let rockbox_info = read_rockbox_info(rockbox_dir)?;
+    let tagcache_format = if let Some(info) = &rockbox_info {
+        println!("Found {}", info.describe());
+        info.tagcache_format()?
+    } else {
+        println!(
+            "No rockbox-info.txt in {}; detecting tagcache format from the database",
+            rockbox_dir.display()
+        );
+        None
+    };
+
+    if playback_log.is_none()
+        && let Some(info) = rockbox_info
+            .as_ref()
+            .filter(|info| !info.writes_playback_log())
+    {
+        bail!(
+            "Rockbox {} does not write playback.log; pass --playback-log to read a log from elsewhere",
+            info.version
+        );
+    }
+    let playback_path = playback_log.unwrap_or_else(|| rockbox_dir.join("playback.log"));
+    if !playback_path.exists() {
+        bail!("Missing playback log at {}", playback_path.display());
+    }
+    Ok((rockbox_info, tagcache_format, playback_path))
+}
+
+fn handle_archive(command: ArchiveCommand, root: &Path) -> Result<()> {
+    match command {
+        ArchiveCommand::Replay(args) => replay_archive(args, root),
+    }
+}
+
+fn replay_archive(args: ReplayArgs, root: &Path) -> Result<()> {
+    let config_path = args.config_path.unwrap_or_else(default_config_path);
+    let mut config = load_config(&config_path)?;
+    let mut accounts = select_accounts(&config, Some(&args.service), Some(&args.username))?;
+    let profile = resolve_profile(
+        &config,
+        args.device.as_deref(),
+        args.rockbox_dir.as_deref(),
+        args.logged_timestamps,
+        root,
+    )?;
+    let log_key = profile.device.as_ref().map_or_else(
+        || {
+            log_id(
+                &args
+                    .playback_log
+                    .unwrap_or_else(|| profile.rockbox_dir.join("playback.log")),
+            )
+        },
+        |device| device.name.clone(),
+    );
+    let archive_dir = archive_dir(&config_path, &log_key);
+    let entries = parse_playback_lines(
+        &read_archive(&archive_dir, args.from, args.to)?,
+        profile.timezone,
+    );
+    if entries.is_empty() {
+        bail!("No archived entries in {}", archive_dir.display());
+    }
+
+    let rockbox_info = read_rockbox_info(&profile.rockbox_dir)?;
+    let tagcache_format = match &rockbox_info {
+        Some(info) => info.tagcache_format()?,
+        None => None,
+    };
+    let candidates = candidates_from_entries(
+        &profile,
+        tagcache_format,
+        rockbox_info.as_ref(),
+        &entries,
+        &accounts,
+    )?;
+    let account = &mut accounts[0];
+    let exclusions = Exclusions::new(&config.exclusions)?;
+    let rewrites = Rewrites::new(&config.rewrites)?;
+    let (mut tracks, skipped) = account_eligible(
+        &candidates,
+        &profile.policy,
+        &exclusions,
+        &rewrites,
+        account,
+    );
+    if args.dry_run {
+        report_eligibility("replay", account, &tracks, &skipped);
+    } else {
+        report_exclusions(account, &skipped);
+    }
     if tracks.is_empty() {
         bail!("No scrobble-eligible tracks found.");
     }
+
+    let ledger_path = ledger_path(&config_path);
+    let mut ledger = load_ledger(&ledger_path)?;
+    let archive_log = archive_ledger_id(&log_key);
+    if !args.force {
+        let eligible = tracks.len();
+        tracks.retain(|track| !ledger.is_delivered(&archive_log, account, track));
+        let delivered = eligible - tracks.len();
+        if tracks.is_empty() {
+            bail!(
+                "All {eligible} tracks already scrobbled to {} for {}; use --force to resubmit them.",
+                account.service,
+                account.username
+            );
+        }
+        if delivered > 0 {
+            println!(
+                "Skipping {delivered} tracks already scrobbled to {} for {}; use --force to resubmit them",
+                account.service, account.username
+            );
+        }
+    }
     if args.dry_run {
-        println!("Would scrobble {} tracks.", tracks.len());
         return Ok(());
     }
 
-    let failures = scrobble_for_accounts(&config, &accounts, &tracks, args.debug_response)?;
+    let queue_path = queue_path(&config_path);
+    let mut queue = load_queue(&queue_path)?;
+    let errors = scrobble_for_account(&config, account, &tracks, args.debug_response);
+    report_scrobbles(account, &tracks, &errors);
+    queue_failures(&mut queue, account, &tracks, &errors);
+    save_queue(&queue, &queue_path)?;
+    record_handled(&mut ledger, &archive_log, account, &tracks, &errors);
+    save_ledger(&ledger, &ledger_path)?;
+    save_session_keys(&mut config, &config_path, &accounts)
+}
 
-    if failures > 0 {
-        println!("Finished with {failures} scrobble failures.");
+fn handle_tags(args: TagsArgs) -> Result<()> {
+    let tagcache_format = match read_rockbox_info(&args.rockbox_dir)? {
+        Some(info) => info.tagcache_format()?,
+        None => None,
+    };
+    let mut paths = args.paths;
+    if paths.is_empty() {
+        let playback_path = args.rockbox_dir.join("playback.log");
+        for entry in parse_playback_log(&playback_path, None)? {
+            if !paths.contains(&entry.path) {
+                paths.push(entry.path);
+            }
+        }
     }
-    if args.truncate {
-        truncate_playback_log(&playback_path)?;
-        println!("Truncated {}", playback_path.display());
+    let mut tagcache = TagCache::new(&args.rockbox_dir, tagcache_format)?;
+    for path in &paths {
+        match tagcache.get_track_info(path)? {
+            Some(info) => println!("{}", serde_json::to_string(&info)?),
+            None => eprintln!("No metadata for {path}"),
+        }
     }
+    tagcache.close();
     Ok(())
 }
 
 fn scrobble_for_accounts(
     config: &config::Config,
-    accounts: &[config::Account],
-    tracks: &[scrobble::ScrobbleTrack],
+    accounts: &mut [config::Account],
+    account_tracks: &[Vec<ScrobbleTrack>],
+    ledger: &mut Ledger,
+    log: &str,
+    queue: &mut Queue,
     debug_response: bool,
-) -> Result<usize> {
+) -> usize {
     let mut failures = 0;
+    for (account, tracks) in accounts.iter_mut().zip(account_tracks) {
+        if tracks.is_empty() {
+            println!(
+                "No eligible tracks for {} {}",
+                account.service, account.username
+            );
+            continue;
+        }
+        let pending: Vec<_> = tracks
+            .iter()
+            .filter(|track| !ledger.is_delivered(log, account, track))
+            .cloned()
+            .collect();
+        if pending.is_empty() {
+            println!(
+                "All {} tracks already scrobbled to {} for {}",
+                tracks.len(),
+                account.service,
+                account.username
+            );
+            continue;
+        }
+        let errors = scrobble_for_account(config, account, &pending, debug_response);
+        report_scrobbles(account, &pending, &errors);
+        failures += queue_failures(queue, account, &pending, &errors);
+        record_handled(ledger, log, account, &pending, &errors);
+    }
+    failures
+}
+
+// Records the plays that were delivered or queued, since the queue delivers the
+// latter. A dropped play stays unrecorded so a later run or an archive replay
+// can submit it again.
+fn record_handled(
+    ledger: &mut Ledger,
+    log: &str,
+    account: &config::Account,
+    tracks: &[ScrobbleTrack],
+    errors: &[ScrobbleFailure],
+) {
+    for (index, track) in tracks.iter().enumerate() {
+        let dropped = errors
+            .iter()
+            .any(|error| error.index == index && !error.is_retryable());
+        if !dropped {
+            ledger.record(log, account, track);
+        }
+    }
+}
+
+// Queues the failures worth retrying and returns how many were queued; plays
+// the service ignored are dropped.
+fn queue_failures(
+    queue: &mut Queue,
+    account: &config::Account,
+    tracks: &[ScrobbleTrack],
+    errors: &[ScrobbleFailure],
+) -> usize {
+    let mut queued = 0;
+    for error in errors.iter().filter(|error| error.is_retryable()) {
+        queue.push(account, &tracks[error.index], &error.message);
+        queued += 1;
+    }
+    report_dropped(account, errors.len() - queued);
+    queued
+}
+
+fn report_dropped(account: &config::Account, dropped: usize) {
+    if dropped > 0 {
+        println!(
+            "Dropped {dropped} scrobbles ignored by {} for {}; they are not retried",
+            account.service, account.username
+        );
+    }
+}
+
+fn flush_queue(
+    config: &config::Config,
+    accounts: &mut [config::Account],
+    queue: &mut Queue,
+    debug_response: bool,
+) -> usize {
+    let mut remaining = 0;
     for account in accounts {
-        let keys = if account.service == "librefm" {
-            ServiceKeys {
-                api_key: "cobblestone".to_string(),
-                api_secret: "cobblestone".to_string(),
-            }
-        } else {
-            let Some(keys) = get_service_keys(config, &account.service) else {
-                println!("Missing API keys for {}", account.service);
-                failures += 1;
-                continue;
-            };
-            keys.clone()
-        };
-        let service = Service::parse(&account.service)?;
-        match ScrobbleClient::new(service, &keys, account, debug_response) {
-            Ok(client) => {
-                let errors = client.scrobble_tracks(tracks);
-                let error_count = errors.len();
-                if error_count == 0 {
-                    println!(
-                        "Scrobbled {} tracks to {} for {}",
-                        tracks.len(),
-                        account.service,
-                        account.username
-                    );
-                } else {
-                    println!(
-                        "Scrobbled {} tracks to {} for {} with {} failures:",
-                        tracks.len(),
-                        account.service,
-                        account.username,
-                        error_count
-                    );
-                    for error in errors {
-                        println!("  {error}");
-                    }
-                    failures += error_count;
-                }
-            }
-            Err(err) => {
-                println!("Failed scrobbling to {}: {err}", account.service);
-                failures += 1;
+        let queued = queue.take_for(account);
+        if queued.is_empty() {
+            continue;
+        }
+        println!(
+            "Retrying {} queued scrobbles to {} for {}",
+            queued.len(),
+            account.service,
+            account.username
+        );
+        let tracks: Vec<_> = queued.iter().map(|entry| entry.track.clone()).collect();
+        let errors = scrobble_for_account(config, account, &tracks, debug_response);
+        report_scrobbles(account, &tracks, &errors);
+        let mut requeued = 0;
+        for error in errors.iter().filter(|error| error.is_retryable()) {
+            let mut entry = queued[error.index].clone();
+            entry.error.clone_from(&error.message);
+            queue.entries.push(entry);
+            requeued += 1;
+        }
+        report_dropped(account, errors.len() - requeued);
+        remaining += requeued;
+    }
+    remaining
+}
+
+fn scrobble_for_account(
+    config: &config::Config,
+    account: &mut config::Account,
+    tracks: &[ScrobbleTrack],
+    debug_response: bool,
+) -> Vec<ScrobbleFailure> {
+    // Nothing was submitted, so the plays themselves were not judged; like an
+    // invalid session, the failure is the account's and the plays are kept.
+    let fail_all = |message: String| {
+        (0..tracks.len())
+            .map(|index| ScrobbleFailure {
+                index,
+                kind: ErrorKind::InvalidSession,
+                message: message.clone(),
+            })
+            .collect()
+    };
+    let service = match Service::resolve(config, &account.service) {
+        Ok(service) => service,
+        Err(err) => return fail_all(err.to_string()),
+    };
+    let keys = service.keys(config, &account.service);
+    match ScrobbleClient::new(service, keys.as_ref(), account, debug_response) {
+        Ok(mut client) => {
+            let failures = client.scrobble_tracks(tracks);
+            if let Some(session_key) = client.session_key() {
+                account.session_key = Some(session_key.to_string());
             }
+            failures
         }
+        Err(err) => fail_all(format!("Failed scrobbling to {}: {err}", account.service)),
     }
-    Ok(failures)
 }
 
-fn truncate_playback_log(path: &Path) -> Result<()> {
-    std::fs::write(path, "").map_err(|err| anyhow::anyhow!(err))
+fn report_scrobbles(
+    account: &config::Account,
+    tracks: &[ScrobbleTrack],
+    errors: &[ScrobbleFailure],
+) {
+    if errors.is_empty() {
+        println!(
+            "Scrobbled {} tracks to {} for {}",
+            tracks.len(),
+            account.service,
+            account.username
+        );
+        return;
+    }
+    if errors.len() == tracks.len()
+        && errors
+            .iter()
+            .all(|error| error.message == errors[0].message)
+    {
+        println!(
+            "Failed scrobbling {} tracks to {} for {}: {}",
+            tracks.len(),
+            account.service,
+            account.username,
+            errors[0].message
+        );
+        return;
+    }
+    println!(
+        "Scrobbled {} tracks to {} for {} with {} failures:",
+        tracks.len(),
+        account.service,
+        account.username,
+        errors.len()
+    );
+    for error in errors {
+        let track = &tracks[error.index];
+        println!("  {} - {}: {}", track.artist, track.title, error.message);
+    }
 }
 
 fn prompt_password_confirm() -> Result<String> {
@@ -0,0 +1,79 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Account, load_state, save_state, state_path};
+use crate::scrobble::ScrobbleTrack;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedScrobble {
+    pub service: String,
+    pub username: String,
+    pub track: ScrobbleTrack,
+    pub error: String,
+    pub queued_at: i64,
+}
+
+impl QueuedScrobble {
+    pub fn belongs_to(&self, account: &Account) -> bool {
+        self.service == account.service && self.username == account.username
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Queue {
+    #[serde(default)]
+    pub entries: Vec<QueuedScrobble>,
+}
+
+impl Queue {
+    pub fn push(&mut self, account: &Account, track: &ScrobbleTrack, error: &str) {
+        let key = track.key();
+        let now = chrono::Utc::now().timestamp();
+        if let Some(existing) = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.belongs_to(account) && entry.track.key() == key)
+        {
+            existing.error = error.to_string();
+            return;
+        }
+        self.entries.push(QueuedScrobble {
+            service: account.service.clone(),
+            username: account.username.clone(),
+            track: track.clone(),
+            error: error.to_string(),
+            queued_at: now,
+        });
+    }
+
+    pub fn take_for(&mut self, account: &Account) -> Vec<QueuedScrobble> {
+        let (taken, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| entry.belongs_to(account));
+        self.entries = kept;
+        taken
+    }
+
+    pub fn drop_matching(&mut self, service: Option<&str>, username: Option<&str>) -> usize {
+        let original_len = self.entries.len();
+        self.entries.retain(|entry| {
+            !(service.is_none_or(|svc| svc == entry.service)
+                && username.is_none_or(|user| user == entry.username))
+        });
+        original_len - self.entries.len()
+    }
+}
+
+pub fn queue_path(config_path: &Path) -> PathBuf {
+    state_path(config_path, "queue.json")
+}
+
+pub fn load_queue(path: &Path) -> Result<Queue> {
+    load_state(path, "scrobble queue")
+}
+
+pub fn save_queue(queue: &Queue, path: &Path) -> Result<()> {
+    save_state(queue, path, "scrobble queue")
+}
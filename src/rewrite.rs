@@ -0,0 +1,85 @@
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+
+use crate::config::{RewriteField, RewriteRule};
+use crate::scrobble::ScrobbleTrack;
+
+// Rewrite rules with their regexes compiled once per run.
+#[derive(Default)]
+pub struct Rewrites {
+    rules: Vec<CompiledRule>,
+}
+
+struct CompiledRule {
+    field: RewriteField,
+    matcher: Matcher,
+    replacement: String,
+}
+
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Rewrites {
+    pub fn new(rules: &[RewriteRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                let matcher = match (&rule.exact, &rule.regex) {
+                    (Some(exact), None) => Matcher::Exact(exact.clone()),
+                    (None, Some(pattern)) => {
+                        Matcher::Regex(Regex::new(pattern).map_err(|err| {
+                            anyhow!("Invalid regex in rewrite rule {}: {err}", index + 1)
+                        })?)
+                    }
+                    _ => bail!(
+                        "Rewrite rule {} needs exactly one of exact or regex",
+                        index + 1
+                    ),
+                };
+                Ok(CompiledRule {
+                    field: rule.field,
+                    matcher,
+                    replacement: rule.replacement.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn apply(&self, track: &mut ScrobbleTrack) {
+        for rule in &self.rules {
+            match rule.field {
+                RewriteField::Artist => rule.rewrite(&mut track.artist),
+                RewriteField::Title => rule.rewrite(&mut track.title),
+                RewriteField::Album => {
+                    if let Some(album) = &mut track.album {
+                        rule.rewrite(album);
+                    }
+                    // A rule that strips the whole album leaves it unset.
+                    track.album.take_if(|album| album.is_empty());
+                }
+            }
+        }
+    }
+}
+
+impl CompiledRule {
+    fn rewrite(&self, value: &mut String) {
+        match &self.matcher {
+            Matcher::Exact(exact) => {
+                if value == exact {
+                    value.clone_from(&self.replacement);
+                }
+            }
+            Matcher::Regex(regex) => {
+                let rewritten = regex.replace_all(value, self.replacement.as_str());
+                if rewritten != value.as_str() {
+                    *value = rewritten.into_owned();
+                }
+            }
+        }
+    }
+}
@@ -5,21 +5,17 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result, bail};
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use chrono::{Local, LocalResult, TimeZone, Utc};
+use chrono::{Local, LocalResult, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
 
-const TAGCACHE_MAGIC: u32 = 0x5443_4810;
-
-const TAG_ARTIST: usize = 0;
-const TAG_ALBUM: usize = 1;
-const TAG_TITLE: usize = 3;
-const TAG_FILENAME: i32 = 4;
-const TAG_LENGTH: usize = 14;
-
-const TAG_COUNT: usize = 23;
+const TAGCACHE_MAGIC_BASE: u32 = 0x5443_4800;
 
 const TAGCACHE_HEADER_SIZE: usize = 12;
+const MASTER_HEADER_SIZE: usize = 24;
 const TAGFILE_ENTRY_HEADER_SIZE: usize = 8;
 const PLAYBACK_LOG_PARTS: usize = 4;
+const ROCKBOX_INFO_FILE: &str = "rockbox-info.txt";
 
 #[derive(Debug, Clone)]
 pub struct PlaybackEntry {
@@ -29,12 +25,255 @@ pub struct PlaybackEntry {
     pub path: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackInfo {
+    pub path: String,
     pub artist: String,
     pub title: String,
     pub album: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub album_artist: Option<String>,
+    pub grouping: Option<String>,
+    pub canonical_artist: Option<String>,
+    pub year: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub track_number: Option<u32>,
+    pub bitrate_kbps: Option<u32>,
     pub duration_seconds: i64,
+    pub play_count: u32,
+    pub rating: u32,
+    pub play_time_ms: i64,
+    pub last_played: u32,
+    pub commit_id: u32,
+    pub mtime: u32,
+    pub last_elapsed_ms: i64,
+    pub last_offset: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RockboxInfo {
+    pub target: String,
+    pub version: String,
+    pub memory_mb: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RockboxBuild {
+    Release { major: u32, minor: u32 },
+    Development,
+}
+
+impl RockboxInfo {
+    pub fn build(&self) -> RockboxBuild {
+        let mut parts = self.version.splitn(2, '.');
+        let major = parts.next().and_then(|value| value.parse::<u32>().ok());
+        let minor = parts.next().and_then(|value| {
+            let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u32>().ok()
+        });
+        match (major, minor) {
+            (Some(major), Some(minor)) => RockboxBuild::Release { major, minor },
+            _ => RockboxBuild::Development,
+        }
+    }
+
+    // Development builds may be on either side of a tagcache format change,
+    // so they return `None` and the format is taken from the database header.
+    pub fn tagcache_format(&self) -> Result<Option<&'static TagcacheFormat>> {
+        let version = match self.build() {
+            RockboxBuild::Development => return Ok(None),
+            RockboxBuild::Release {
+                major: 3,
+                minor: 15,
+            } => 0x0f,
+            RockboxBuild::Release { major: 4, .. } => 0x10,
+            RockboxBuild::Release { .. } => bail!(
+                "Rockbox {} on {} is not supported; cobblestone supports 3.15, 4.x and development builds",
+                self.version,
+                self.target
+            ),
+        };
+        Ok(TAGCACHE_FORMATS
+            .iter()
+            .find(|format| format.version == version))
+    }
+
+    pub fn writes_playback_log(&self) -> bool {
+        match self.build() {
+            RockboxBuild::Development => true,
+            RockboxBuild::Release { major, .. } => major >= 4,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let description = format!("Rockbox {} on {}", self.version, self.target);
+        match self.memory_mb {
+            Some(memory_mb) => format!("{description} ({memory_mb} MB)"),
+            None => description,
+        }
+    }
+}
+
+pub fn read_rockbox_info(rockbox_dir: &Path) -> Result<Option<RockboxInfo>> {
+    let path = rockbox_dir.join(ROCKBOX_INFO_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let raw = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading {}", path.display()))?;
+    let mut target = None;
+    let mut version = None;
+    let mut memory_mb = None;
+    for line in raw.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "Target" => target = Some(value.to_string()),
+            "Version" => version = Some(value.to_string()),
+            "Memory" => memory_mb = value.parse::<u32>().ok(),
+            _ => {}
+        }
+    }
+    let (Some(target), Some(version)) = (target, version) else {
+        bail!("Missing target or version in {}", path.display());
+    };
+    Ok(Some(RockboxInfo {
+        target,
+        version,
+        memory_mb,
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Artist,
+    Album,
+    Genre,
+    Title,
+    Filename,
+    Composer,
+    Comment,
+    AlbumArtist,
+    Grouping,
+    Year,
+    DiscNumber,
+    TrackNumber,
+    CanonicalArtist,
+    Bitrate,
+    Length,
+    PlayCount,
+    Rating,
+    PlayTime,
+    LastPlayed,
+    CommitId,
+    Mtime,
+    LastElapsed,
+    LastOffset,
+}
+
+const TAGS_V15: &[Tag] = &[
+    Tag::Artist,
+    Tag::Album,
+    Tag::Genre,
+    Tag::Title,
+    Tag::Filename,
+    Tag::Composer,
+    Tag::Comment,
+    Tag::AlbumArtist,
+    Tag::Grouping,
+    Tag::Year,
+    Tag::DiscNumber,
+    Tag::TrackNumber,
+    Tag::Bitrate,
+    Tag::Length,
+    Tag::PlayCount,
+    Tag::Rating,
+    Tag::PlayTime,
+    Tag::LastPlayed,
+    Tag::CommitId,
+    Tag::Mtime,
+    Tag::LastElapsed,
+    Tag::LastOffset,
+];
+
+const TAGS_V16: &[Tag] = &[
+    Tag::Artist,
+    Tag::Album,
+    Tag::Genre,
+    Tag::Title,
+    Tag::Filename,
+    Tag::Composer,
+    Tag::Comment,
+    Tag::AlbumArtist,
+    Tag::Grouping,
+    Tag::Year,
+    Tag::DiscNumber,
+    Tag::TrackNumber,
+    Tag::CanonicalArtist,
+    Tag::Bitrate,
+    Tag::Length,
+    Tag::PlayCount,
+    Tag::Rating,
+    Tag::PlayTime,
+    Tag::LastPlayed,
+    Tag::CommitId,
+    Tag::Mtime,
+    Tag::LastElapsed,
+    Tag::LastOffset,
+];
+
+#[derive(Debug)]
+pub struct TagcacheFormat {
+    pub version: u32,
+    pub description: &'static str,
+    tags: &'static [Tag],
+    master_header_size: usize,
+}
+
+// Rockbox bumps the low byte of the tagcache magic whenever the on-disk
+// layout changes. Version 0x0f is used by 3.15, 0x10 added the canonical
+// artist tag and is used by 4.0 and current development builds.
+pub const TAGCACHE_FORMATS: &[TagcacheFormat] = &[
+    TagcacheFormat {
+        version: 0x0f,
+        description: "Rockbox 3.15",
+        tags: TAGS_V15,
+        master_header_size: MASTER_HEADER_SIZE,
+    },
+    TagcacheFormat {
+        version: 0x10,
+        description: "Rockbox 4.0",
+        tags: TAGS_V16,
+        master_header_size: MASTER_HEADER_SIZE,
+    },
+];
+
+impl TagcacheFormat {
+    pub fn from_magic(magic: u32) -> Option<&'static Self> {
+        TAGCACHE_FORMATS
+            .iter()
+            .find(|format| format.magic() == magic)
+    }
+
+    pub fn magic(&self) -> u32 {
+        TAGCACHE_MAGIC_BASE | self.version
+    }
+
+    fn tag_count(&self) -> usize {
+        self.tags.len()
+    }
+
+    fn entry_size(&self) -> usize {
+        (self.tag_count() + 1) * 4
+    }
+
+    fn tag_index(&self, tag: Tag) -> Option<usize> {
+        self.tags.iter().position(|candidate| *candidate == tag)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -63,25 +302,33 @@ pub struct TagCache {
     rockbox_dir: PathBuf,
     master_path: PathBuf,
     endian: Endian,
-    entry_size: usize,
-    master_header_size: usize,
+    format: &'static TagcacheFormat,
     path_index: Option<HashMap<String, i32>>,
     tag_files: HashMap<i32, File>,
 }
 
 impl TagCache {
-    pub fn new(rockbox_dir: &Path) -> Result<Self> {
+    pub fn new(rockbox_dir: &Path, expected: Option<&'static TagcacheFormat>) -> Result<Self> {
         let master_path = rockbox_dir.join("database_idx.tcd");
         if !master_path.exists() {
             bail!("Missing tagcache file: {}", master_path.display());
         }
-        let endian = Self::detect_endian(&master_path)?;
+        let (endian, format) = Self::detect_format(&master_path)?;
+        if let Some(expected) = expected
+            && expected.version != format.version
+        {
+            bail!(
+                "Tagcache in {} uses the {} format but the device runs {}; rebuild the database on the player",
+                rockbox_dir.display(),
+                format.description,
+                expected.description
+            );
+        }
         Ok(Self {
             rockbox_dir: rockbox_dir.to_path_buf(),
             master_path,
             endian,
-            entry_size: (TAG_COUNT + 1) * 4,
-            master_header_size: 24,
+            format,
             path_index: None,
             tag_files: HashMap::new(),
         })
@@ -91,25 +338,33 @@ impl TagCache {
         self.tag_files.clear();
     }
 
-    fn detect_endian(path: &Path) -> Result<Endian> {
+    fn detect_format(path: &Path) -> Result<(Endian, &'static TagcacheFormat)> {
         let mut handle = File::open(path)
             .with_context(|| format!("Failed opening tagcache {}", path.display()))?;
         let mut magic_bytes = [0u8; 4];
         handle
             .read_exact(&mut magic_bytes)
             .context("Failed reading tagcache header")?;
-        if LittleEndian::read_u32(&magic_bytes) == TAGCACHE_MAGIC {
-            return Ok(Endian::Little);
-        }
-        if BigEndian::read_u32(&magic_bytes) == TAGCACHE_MAGIC {
-            return Ok(Endian::Big);
+        for endian in [Endian::Little, Endian::Big] {
+            let magic = endian.read_u32(&magic_bytes);
+            if magic & 0xffff_ff00 != TAGCACHE_MAGIC_BASE {
+                continue;
+            }
+            let Some(format) = TagcacheFormat::from_magic(magic) else {
+                bail!(
+                    "Unsupported tagcache version 0x{:02x} in {}",
+                    magic & 0xff,
+                    path.display()
+                );
+            };
+            return Ok((endian, format));
         }
         bail!("Unrecognized tagcache magic in {}", path.display());
     }
 
     fn with_tag_file<T>(&mut self, tag: i32, f: impl FnOnce(&mut File) -> Result<T>) -> Result<T> {
         if !self.tag_files.contains_key(&tag) {
-            let path = self.rockbox_dir.join(format!("database_{tag}.tcd"));
+            let path = self.tag_file_path(tag);
             let handle = File::open(&path)
                 .with_context(|| format!("Failed opening tagcache {}", path.display()))?;
             self.tag_files.insert(tag, handle);
@@ -118,6 +373,10 @@ impl TagCache {
         f(handle)
     }
 
+    fn tag_file_path(&self, tag: i32) -> PathBuf {
+        self.rockbox_dir.join(format!("database_{tag}.tcd"))
+    }
+
     fn read_header(endian: Endian, handle: &mut File) -> Result<(u32, u32, u32)> {
         let mut header = [0u8; TAGCACHE_HEADER_SIZE];
         handle
@@ -160,10 +419,12 @@ impl TagCache {
     fn load_path_index(&mut self) -> Result<&HashMap<String, i32>> {
         if self.path_index.is_none() {
             let endian = self.endian;
-            let index = self.with_tag_file(TAG_FILENAME, |handle| {
+            let expected_magic = self.format.magic();
+            let filename_tag = self.tag_file_id(Tag::Filename)?;
+            let index = self.with_tag_file(filename_tag, |handle| {
                 handle.seek(SeekFrom::Start(0))?;
                 let (magic, _data_size, entry_count) = Self::read_header(endian, handle)?;
-                if magic != TAGCACHE_MAGIC {
+                if magic != expected_magic {
                     bail!("Tagcache filename index has invalid header");
                 }
                 let mut index = HashMap::new();
@@ -201,24 +462,95 @@ impl TagCache {
             return Ok(None);
         };
         let entry = self.read_index_entry(idx_id)?;
-        let artist = self.read_tag_string(tag_to_i32(TAG_ARTIST), entry[TAG_ARTIST])?;
-        let title = self.read_tag_string(tag_to_i32(TAG_TITLE), entry[TAG_TITLE])?;
-        let album = self.read_tag_string(tag_to_i32(TAG_ALBUM), entry[TAG_ALBUM])?;
+        let artist = self.read_entry_string(&entry, Tag::Artist)?;
+        let title = self.read_entry_string(&entry, Tag::Title)?;
         let artist = artist.unwrap_or_default();
         let title = title.unwrap_or_default();
-        let duration_ms = i64::from(entry[TAG_LENGTH].max(0));
-        let duration_seconds = duration_ms / 1000;
         if artist.is_empty() || title.is_empty() {
             return Ok(None);
         }
+        let duration_ms = i64::from(self.entry_value(&entry, Tag::Length).max(0));
         Ok(Some(TrackInfo {
+            path: path.to_string(),
             artist,
             title,
-            album: album.filter(|value| !value.is_empty()),
-            duration_seconds,
+            album: self.read_optional_string(&entry, Tag::Album)?,
+            genre: self.read_optional_string(&entry, Tag::Genre)?,
+            composer: self.read_optional_string(&entry, Tag::Composer)?,
+            comment: self.read_optional_string(&entry, Tag::Comment)?,
+            album_artist: self.read_optional_string(&entry, Tag::AlbumArtist)?,
+            grouping: self.read_optional_string(&entry, Tag::Grouping)?,
+            canonical_artist: self.read_string_if_stored(&entry, Tag::CanonicalArtist)?,
+            year: self.optional_number(&entry, Tag::Year),
+            disc_number: self.optional_number(&entry, Tag::DiscNumber),
+            track_number: self.optional_number(&entry, Tag::TrackNumber),
+            bitrate_kbps: self.optional_number(&entry, Tag::Bitrate),
+            duration_seconds: duration_ms / 1000,
+            play_count: self.number(&entry, Tag::PlayCount),
+            rating: self.number(&entry, Tag::Rating),
+            play_time_ms: i64::from(self.entry_value(&entry, Tag::PlayTime).max(0)),
+            last_played: self.number(&entry, Tag::LastPlayed),
+            commit_id: self.number(&entry, Tag::CommitId),
+            mtime: self.number(&entry, Tag::Mtime),
+            last_elapsed_ms: i64::from(self.entry_value(&entry, Tag::LastElapsed).max(0)),
+            last_offset: self.number(&entry, Tag::LastOffset),
         }))
     }
 
+    fn read_optional_string(&mut self, entry: &[i32], tag: Tag) -> Result<Option<String>> {
+        Ok(self
+            .read_entry_string(entry, tag)?
+            .filter(|value| !value.is_empty()))
+    }
+
+    // Some 4.0 databases have no file for the canonical artist; a tag file
+    // that is missing reads as unset instead of failing the whole lookup.
+    fn read_string_if_stored(&mut self, entry: &[i32], tag: Tag) -> Result<Option<String>> {
+        let Some(index) = self.format.tag_index(tag) else {
+            return Ok(None);
+        };
+        let tag_file = i32::try_from(index).expect("tag index fits in i32");
+        if !self.tag_file_path(tag_file).is_file() {
+            return Ok(None);
+        }
+        self.read_optional_string(entry, tag)
+    }
+
+    // Numeric tags are stored in the index entry itself rather than in a
+    // tag file. Rockbox uses zero or a negative value for unknown fields.
+    fn number(&self, entry: &[i32], tag: Tag) -> u32 {
+        u32::try_from(self.entry_value(entry, tag)).unwrap_or(0)
+    }
+
+    fn optional_number(&self, entry: &[i32], tag: Tag) -> Option<u32> {
+        Some(self.number(entry, tag)).filter(|value| *value > 0)
+    }
+
+    fn tag_file_id(&self, tag: Tag) -> Result<i32> {
+        let Some(index) = self.format.tag_index(tag) else {
+            bail!(
+                "Tag {tag:?} is not stored by {} tagcache",
+                self.format.description
+            );
+        };
+        Ok(i32::try_from(index).expect("tag index fits in i32"))
+    }
+
+    fn entry_value(&self, entry: &[i32], tag: Tag) -> i32 {
+        self.format
+            .tag_index(tag)
+            .and_then(|index| entry.get(index).copied())
+            .unwrap_or(0)
+    }
+
+    fn read_entry_string(&mut self, entry: &[i32], tag: Tag) -> Result<Option<String>> {
+        if self.format.tag_index(tag).is_none() {
+            return Ok(None);
+        }
+        let tag_file = self.tag_file_id(tag)?;
+        self.read_tag_string(tag_file, self.entry_value(entry, tag))
+    }
+
     fn read_index_entry(&self, idx_id: i32) -> Result<Vec<i32>> {
         if idx_id < 0 {
             bail!("Invalid tagcache index id {idx_id}");
@@ -226,16 +558,17 @@ impl TagCache {
         let mut handle = File::open(&self.master_path)
             .with_context(|| format!("Failed opening tagcache {}", self.master_path.display()))?;
         let idx_id = u64::try_from(idx_id).context("Invalid tagcache index id")?;
-        let header_size =
-            u64::try_from(self.master_header_size).context("Invalid tagcache header size")?;
-        let entry_size = u64::try_from(self.entry_size).context("Invalid tagcache entry size")?;
+        let header_size = u64::try_from(self.format.master_header_size)
+            .context("Invalid tagcache header size")?;
+        let entry_size =
+            u64::try_from(self.format.entry_size()).context("Invalid tagcache entry size")?;
         let offset = header_size + (idx_id * entry_size);
         handle.seek(SeekFrom::Start(offset))?;
-        let mut raw = vec![0u8; self.entry_size];
+        let mut raw = vec![0u8; self.format.entry_size()];
         handle
             .read_exact(&mut raw)
             .with_context(|| format!("Short read for index entry {idx_id}"))?;
-        let mut values = Vec::with_capacity(TAG_COUNT + 1);
+        let mut values = Vec::with_capacity(self.format.tag_count() + 1);
         for chunk in raw.chunks_exact(4) {
             values.push(self.endian.read_i32(chunk));
         }
@@ -243,9 +576,15 @@ impl TagCache {
     }
 }
 
-pub fn parse_playback_log(path: &Path) -> Result<Vec<PlaybackEntry>> {
+// Rockbox logs wall-clock time of the player; `timezone` names the zone it was
+// set to, defaulting to the zone of this machine.
+pub fn parse_playback_log(path: &Path, timezone: Option<Tz>) -> Result<Vec<PlaybackEntry>> {
     let raw = std::fs::read_to_string(path)
         .with_context(|| format!("Failed reading playback log {}", path.display()))?;
+    Ok(parse_playback_lines(&raw, timezone))
+}
+
+pub fn parse_playback_lines(raw: &str, timezone: Option<Tz>) -> Vec<PlaybackEntry> {
     let mut entries = Vec::new();
     for line in raw.lines() {
         let cleaned = line.trim();
@@ -259,7 +598,7 @@ pub fn parse_playback_log(path: &Path) -> Result<Vec<PlaybackEntry>> {
         let Ok(timestamp) = parts[0].parse::<i64>() else {
             continue;
         };
-        let timestamp = local_timestamp_to_utc(timestamp);
+        let timestamp = local_timestamp_to_utc(timestamp, timezone);
         let Ok(elapsed_ms) = parts[1].parse::<i64>() else {
             continue;
         };
@@ -273,22 +612,113 @@ pub fn parse_playback_log(path: &Path) -> Result<Vec<PlaybackEntry>> {
             path: parts[3].to_string(),
         });
     }
-    Ok(entries)
+    entries
 }
 
-fn tag_to_i32(tag: usize) -> i32 {
-    i32::try_from(tag).expect("tag constants fit in i32")
+// The player's clock keeps the device's local time, so the calendar date of a
+// line's raw timestamp is the day of the play where the device is.
+pub fn playback_line_date(line: &str) -> Option<NaiveDate> {
+    let timestamp = line.trim().split(':').next()?.parse::<i64>().ok()?;
+    chrono::DateTime::<Utc>::from_timestamp(timestamp, 0).map(|dt| dt.date_naive())
 }
 
-fn local_timestamp_to_utc(timestamp: i64) -> i64 {
+fn local_timestamp_to_utc(timestamp: i64, timezone: Option<Tz>) -> i64 {
     let Some(utc_dt) = chrono::DateTime::<Utc>::from_timestamp(timestamp, 0) else {
         return timestamp;
     };
     let local_naive = utc_dt.naive_utc();
-    match Local.from_local_datetime(&local_naive) {
-        LocalResult::Single(dt) | LocalResult::Ambiguous(dt, _) => {
-            dt.with_timezone(&Utc).timestamp()
-        }
+    let converted = match timezone {
+        Some(timezone) => timezone
+            .from_local_datetime(&local_naive)
+            .map(|dt| dt.timestamp()),
+        None => Local
+            .from_local_datetime(&local_naive)
+            .map(|dt| dt.timestamp()),
+    };
+    match converted {
+        LocalResult::Single(utc) | LocalResult::Ambiguous(utc, _) => utc,
         LocalResult::None => timestamp,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    // Writes a master index holding only a header and returns its directory.
+    fn write_master(name: &str, magic: [u8; 4]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cobblestone-{name}-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut header = magic.to_vec();
+        header.resize(MASTER_HEADER_SIZE, 0);
+        fs::write(dir.join("database_idx.tcd"), header).unwrap();
+        dir
+    }
+
+    fn detect(name: &str, magic: [u8; 4]) -> Result<(Endian, &'static TagcacheFormat)> {
+        let dir = write_master(name, magic);
+        let detected = TagCache::detect_format(&dir.join("database_idx.tcd"));
+        fs::remove_dir_all(&dir).unwrap();
+        detected
+    }
+
+    #[test]
+    fn detects_each_version_in_both_byte_orders() {
+        for format in TAGCACHE_FORMATS {
+            let magic = format.magic();
+            let (endian, detected) = detect("le", magic.to_le_bytes()).unwrap();
+            assert!(matches!(endian, Endian::Little));
+            assert_eq!(detected.version, format.version);
+            let (endian, detected) = detect("be", magic.to_be_bytes()).unwrap();
+            assert!(matches!(endian, Endian::Big));
+            assert_eq!(detected.version, format.version);
+        }
+    }
+
+    #[test]
+    fn rejects_a_wrong_magic() {
+        let err = detect("bad-magic", *b"ID3\x03").unwrap_err();
+        assert!(err.to_string().starts_with("Unrecognized tagcache magic"));
+    }
+
+    #[test]
+    fn rejects_an_unknown_version() {
+        let err = detect("old-version", (TAGCACHE_MAGIC_BASE | 0x0e).to_le_bytes()).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Unsupported tagcache version 0x0e")
+        );
+    }
+
+    #[test]
+    fn rejects_a_tagcache_from_another_build() {
+        let v15 = TagcacheFormat::from_magic(TAGCACHE_MAGIC_BASE | 0x0f).unwrap();
+        let v16 = TagcacheFormat::from_magic(TAGCACHE_MAGIC_BASE | 0x10).unwrap();
+        let dir = write_master("mismatch", v15.magic().to_le_bytes());
+        let result = TagCache::new(&dir, Some(v16));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn canonical_artist_shifts_the_v16_layout() {
+        let v15 = TagcacheFormat::from_magic(TAGCACHE_MAGIC_BASE | 0x0f).unwrap();
+        let v16 = TagcacheFormat::from_magic(TAGCACHE_MAGIC_BASE | 0x10).unwrap();
+        assert_eq!(v15.tag_count(), 22);
+        assert_eq!(v16.tag_count(), 23);
+        assert_eq!(v15.entry_size(), 23 * 4);
+        assert_eq!(v16.entry_size(), 24 * 4);
+        assert_eq!(v15.tag_index(Tag::CanonicalArtist), None);
+        assert_eq!(v16.tag_index(Tag::CanonicalArtist), Some(12));
+        // Tags stored as strings keep their file numbers across versions.
+        for tag in [Tag::Artist, Tag::Title, Tag::Filename, Tag::TrackNumber] {
+            assert_eq!(v15.tag_index(tag), v16.tag_index(tag));
+        }
+        assert_eq!(v15.tag_index(Tag::Length), Some(13));
+        assert_eq!(v16.tag_index(Tag::Length), Some(14));
+        assert_eq!(v16.tag_index(Tag::LastOffset), Some(22));
+    }
+}
@@ -1,52 +1,445 @@
+use std::fmt;
+
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-use crate::rockbox::{PlaybackEntry, TagCache};
+use crate::config::EligibilityOverrides;
+use crate::exclusion::Exclusions;
+use crate::rockbox::{PlaybackEntry, RockboxInfo, TagCache};
 
 pub const MIN_TRACK_SECONDS: i64 = 30;
+pub const MIN_PLAYED_PERCENT: i64 = 50;
+pub const MAX_REQUIRED_SECONDS: i64 = 240;
+// Longest pause after which a fragment still continues the previous one.
+pub const MAX_PAUSE_SECONDS: i64 = 1800;
+// Rockbox logs the elapsed time at the last position update, which trails the
+// end of a track that played to completion by up to a few seconds.
+const FINISHED_SLACK_MS: i64 = 5000;
+// Log timestamps have whole-second precision while elapsed times are in
+// milliseconds.
+const COALESCE_SLACK_MS: i64 = 2000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EligibilityPolicy {
+    pub min_track_seconds: i64,
+    pub min_played_percent: i64,
+    pub max_required_seconds: i64,
+    // Only plays that reached the end of the track count.
+    pub finished_only: bool,
+}
+
+impl Default for EligibilityPolicy {
+    fn default() -> Self {
+        Self {
+            min_track_seconds: MIN_TRACK_SECONDS,
+            min_played_percent: MIN_PLAYED_PERCENT,
+            max_required_seconds: MAX_REQUIRED_SECONDS,
+            finished_only: false,
+        }
+    }
+}
+
+impl EligibilityPolicy {
+    pub fn with_overrides(mut self, overrides: &EligibilityOverrides) -> Self {
+        if let Some(seconds) = overrides.min_track_seconds {
+            self.min_track_seconds = i64::from(seconds);
+        }
+        if let Some(percent) = overrides.min_played_percent {
+            self.min_played_percent = i64::from(percent);
+        }
+        if let Some(seconds) = overrides.max_required_seconds {
+            self.max_required_seconds = i64::from(seconds);
+        }
+        if let Some(finished_only) = overrides.finished_only {
+            self.finished_only = finished_only;
+        }
+        self
+    }
+
+    pub fn describe(&self) -> String {
+        let played = if self.finished_only {
+            "played to the end".to_string()
+        } else {
+            format!(
+                "played for {}% of their length or {} seconds, whichever is less",
+                self.min_played_percent, self.max_required_seconds
+            )
+        };
+        format!(
+            "tracks of at least {} seconds, {played}",
+            self.min_track_seconds
+        )
+    }
+}
+
+// Rockbox writes a log line when a track stops or changes, so the logged time
+// is when the listen ended. Scrobbles are stamped with when it started unless
+// the logged time is asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampMode {
+    #[default]
+    Start,
+    Logged,
+}
+
+impl TimestampMode {
+    pub fn from_logged(logged: bool) -> Self {
+        if logged {
+            TimestampMode::Logged
+        } else {
+            TimestampMode::Start
+        }
+    }
 
+    fn timestamp(self, listen: &Listen) -> i64 {
+        match self {
+            TimestampMode::Start => listen.started_at,
+            TimestampMode::Logged => listen.entry.timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    Excluded(String),
+    UnknownLength,
+    TooShort { seconds: i64, minimum: i64 },
+    NotFinished { played: i64, seconds: i64 },
+    PlayedTooLittle { played: i64, required: i64 },
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Excluded(rule) => write!(f, "excluded by rule {rule}"),
+            SkipReason::UnknownLength => write!(f, "track length unknown"),
+            SkipReason::TooShort { seconds, minimum } => {
+                write!(f, "track is {seconds} s, shorter than {minimum} s")
+            }
+            SkipReason::NotFinished { played, seconds } => {
+                write!(f, "played {played} of {seconds} s, not to the end")
+            }
+            SkipReason::PlayedTooLittle { played, required } => {
+                write!(f, "played {played} s, {required} s required")
+            }
+        }
+    }
+}
+
+// A playback entry with its tagcache metadata, before any eligibility rule
+// is applied; accounts with different policies pick from the same list.
 #[derive(Debug, Clone)]
+pub struct Candidate {
+    pub entry: PlaybackEntry,
+    pub track: ScrobbleTrack,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScrobbleTrack {
+    pub path: String,
     pub artist: String,
     pub title: String,
     pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub mbid: Option<String>,
+    pub chosen_by_user: bool,
+    #[serde(default)]
+    pub player: Option<RockboxInfo>,
     pub timestamp: i64,
     pub duration: i64,
+    // Log time of the first line of the listen. Unlike `timestamp` it does not
+    // depend on the timestamp mode or on how fragments were joined.
+    #[serde(default)]
+    pub logged_at: Option<i64>,
+}
+
+impl ScrobbleTrack {
+    // Identifies the play in the playback log it came from. Queued scrobbles
+    // saved before `logged_at` existed fall back to the submitted timestamp.
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.logged_at.unwrap_or(self.timestamp), self.path)
+    }
+}
+
+// One listen of a track: a playback entry, possibly joined from several
+// fragments, with the elapsed times summed and the timestamp of the last one.
+#[derive(Debug, Clone)]
+pub struct Listen {
+    pub entry: PlaybackEntry,
+    // Log time of the first fragment.
+    pub logged_at: i64,
+    // When playback of the first fragment started.
+    pub started_at: i64,
+}
+
+impl Listen {
+    fn new(entry: &PlaybackEntry) -> Self {
+        Self {
+            entry: entry.clone(),
+            logged_at: entry.timestamp,
+            started_at: entry.timestamp - entry.elapsed_ms.max(0) / 1000,
+        }
+    }
 }
 
+// Looks up every listen in the tagcache. Paths without metadata are only
+// returned as missing for listens that some of `policies` would accept, so
+// skipped short plays of unindexed files are not reported.
 pub fn build_scrobble_tracks(
     playback_entries: &[PlaybackEntry],
     tagcache: &mut TagCache,
-) -> Result<(Vec<ScrobbleTrack>, Vec<String>)> {
-    let mut tracks = Vec::new();
+    player: Option<&RockboxInfo>,
+    timestamps: TimestampMode,
+    max_pause_seconds: i64,
+    policies: &[EligibilityPolicy],
+) -> Result<(Vec<Candidate>, Vec<String>)> {
+    let mut candidates = Vec::new();
     let mut missing = Vec::new();
-    for entry in playback_entries {
-        if !is_scrobble_eligible(entry) {
-            continue;
-        }
+    for listen in &coalesce_entries(playback_entries, max_pause_seconds) {
+        let entry = &listen.entry;
         let info = tagcache.get_track_info(&entry.path)?;
         let Some(info) = info else {
-            missing.push(entry.path.clone());
+            if policies
+                .iter()
+                .any(|policy| skip_reason(entry, policy).is_none())
+            {
+                missing.push(entry.path.clone());
+            }
             continue;
         };
-        tracks.push(ScrobbleTrack {
-            artist: info.artist,
-            title: info.title,
-            album: info.album,
-            timestamp: entry.timestamp,
-            duration: info.duration_seconds,
+        candidates.push(Candidate {
+            entry: entry.clone(),
+            track: ScrobbleTrack {
+                path: entry.path.clone(),
+                artist: info.artist,
+                title: info.title,
+                album: info.album,
+                album_artist: info.album_artist,
+                track_number: info.track_number,
+                // The tagcache does not store MusicBrainz identifiers.
+                mbid: None,
+                chosen_by_user: true,
+                player: player.cloned(),
+                timestamp: timestamps.timestamp(listen),
+                duration: info.duration_seconds,
+                logged_at: Some(listen.logged_at),
+            },
+            genre: info.genre,
+            comment: info.comment,
         });
     }
-    Ok((tracks, missing))
+    Ok((candidates, missing))
+}
+
+// Rockbox logs a line whenever playback of a track stops, so a listen that was
+// paused or cut short by a power-off shows up as several lines for the same
+// path, each with only part of the elapsed time. Consecutive lines are joined
+// when each fragment started after the previous one was logged, but no more
+// than `max_pause_seconds` later, and together they do not exceed the track
+// length.
+pub fn coalesce_entries(entries: &[PlaybackEntry], max_pause_seconds: i64) -> Vec<Listen> {
+    let mut coalesced: Vec<Listen> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if let Some(previous) = coalesced.last_mut()
+            && continues_listen(&previous.entry, entry, max_pause_seconds)
+        {
+            previous.entry.timestamp = entry.timestamp;
+            previous.entry.elapsed_ms += entry.elapsed_ms;
+            continue;
+        }
+        coalesced.push(Listen::new(entry));
+    }
+    coalesced
+}
+
+fn continues_listen(
+    previous: &PlaybackEntry,
+    entry: &PlaybackEntry,
+    max_pause_seconds: i64,
+) -> bool {
+    let pause_ms = entry.timestamp * 1000 - entry.elapsed_ms - previous.timestamp * 1000;
+    entry.path == previous.path
+        && entry.total_ms == previous.total_ms
+        && entry.total_ms > 0
+        && pause_ms >= -COALESCE_SLACK_MS
+        && pause_ms <= max_pause_seconds * 1000 + COALESCE_SLACK_MS
+        && previous.elapsed_ms + entry.elapsed_ms <= entry.total_ms + COALESCE_SLACK_MS
+}
+
+// Splits candidates into the tracks an account receives under `policy` and
+// `exclusions`, and the skipped ones.
+pub fn select_eligible<'a>(
+    candidates: &'a [Candidate],
+    policy: &EligibilityPolicy,
+    exclusions: &Exclusions,
+    account_id: &str,
+) -> (Vec<ScrobbleTrack>, Vec<(&'a Candidate, SkipReason)>) {
+    let mut tracks = Vec::new();
+    let mut skipped = Vec::new();
+    for candidate in candidates {
+        let reason = match exclusions.matching(candidate, account_id) {
+            Some(rule) => Some(SkipReason::Excluded(rule.to_string())),
+            None => skip_reason(&candidate.entry, policy),
+        };
+        match reason {
+            None => tracks.push(candidate.track.clone()),
+            Some(reason) => skipped.push((candidate, reason)),
+        }
+    }
+    (tracks, skipped)
 }
 
-fn is_scrobble_eligible(entry: &PlaybackEntry) -> bool {
+fn skip_reason(entry: &PlaybackEntry, policy: &EligibilityPolicy) -> Option<SkipReason> {
     if entry.total_ms <= 0 {
-        return false;
+        return Some(SkipReason::UnknownLength);
     }
     let total_seconds = entry.total_ms / 1000;
-    if total_seconds < MIN_TRACK_SECONDS {
-        return false;
+    if total_seconds < policy.min_track_seconds {
+        return Some(SkipReason::TooShort {
+            seconds: total_seconds,
+            minimum: policy.min_track_seconds,
+        });
+    }
+    if policy.finished_only {
+        return (entry.elapsed_ms < entry.total_ms - FINISHED_SLACK_MS).then_some(
+            SkipReason::NotFinished {
+                played: entry.elapsed_ms / 1000,
+                seconds: total_seconds,
+            },
+        );
+    }
+    let min_played_ms =
+        (entry.total_ms * policy.min_played_percent / 100).min(policy.max_required_seconds * 1000);
+    (entry.elapsed_ms < min_played_ms).then_some(SkipReason::PlayedTooLittle {
+        played: entry.elapsed_ms / 1000,
+        required: (min_played_ms + 999) / 1000,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAUSE: i64 = MAX_PAUSE_SECONDS;
+
+    fn entry(
+        timestamp: i64,
+        elapsed_seconds: i64,
+        total_seconds: i64,
+        path: &str,
+    ) -> PlaybackEntry {
+        PlaybackEntry {
+            timestamp,
+            elapsed_ms: elapsed_seconds * 1000,
+            total_ms: total_seconds * 1000,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn joins_a_paused_listen() {
+        // 100 s played, paused for 60 s, then 200 s more.
+        let entries = [
+            entry(1_000, 100, 600, "/a.mp3"),
+            entry(1_260, 200, 600, "/a.mp3"),
+        ];
+        let coalesced = coalesce_entries(&entries, PAUSE);
+        assert_eq!(coalesced.len(), 1);
+        assert_eq!(coalesced[0].entry.elapsed_ms, 300_000);
+        assert_eq!(coalesced[0].entry.timestamp, 1_260);
+        assert_eq!(coalesced[0].logged_at, 1_000);
+        // The listen started with the first fragment, not 300 s before the
+        // last log line.
+        assert_eq!(coalesced[0].started_at, 900);
+    }
+
+    #[test]
+    fn joins_a_chain_of_fragments() {
+        let entries = [
+            entry(1_000, 100, 600, "/a.mp3"),
+            entry(1_200, 100, 600, "/a.mp3"),
+            entry(1_400, 100, 600, "/a.mp3"),
+        ];
+        let coalesced = coalesce_entries(&entries, PAUSE);
+        assert_eq!(coalesced.len(), 1);
+        assert_eq!(coalesced[0].entry.elapsed_ms, 300_000);
+        assert_eq!(coalesced[0].started_at, 900);
+    }
+
+    #[test]
+    fn joins_a_resume_after_power_off_within_the_pause_window() {
+        // The player was off for 20 minutes before playback resumed.
+        let entries = [
+            entry(1_000, 100, 600, "/a.mp3"),
+            entry(1_000 + 1_200 + 150, 150, 600, "/a.mp3"),
+        ];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 1);
+    }
+
+    #[test]
+    fn keeps_fragments_apart_when_the_pause_is_too_long() {
+        // Two separate 45% plays hours apart are not one listen.
+        let entries = [
+            entry(1_000, 270, 600, "/a.mp3"),
+            entry(1_000 + 4 * 3_600 + 270, 270, 600, "/a.mp3"),
+        ];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 2);
+        assert_eq!(coalesce_entries(&entries, 5 * 3_600).len(), 1);
+    }
+
+    #[test]
+    fn keeps_a_replay_of_the_same_track_apart() {
+        let entries = [
+            entry(1_000, 600, 600, "/a.mp3"),
+            entry(1_300, 300, 600, "/a.mp3"),
+        ];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 2);
+    }
+
+    #[test]
+    fn stamps_a_single_fragment_with_its_start() {
+        let listen = &coalesce_entries(&[entry(1_000, 190, 200, "/a.mp3")], PAUSE)[0];
+        assert_eq!(listen.logged_at, 1_000);
+        assert_eq!(listen.started_at, 810);
+        assert_eq!(TimestampMode::Start.timestamp(listen), 810);
+        assert_eq!(TimestampMode::Logged.timestamp(listen), 1_000);
+    }
+
+    #[test]
+    fn keeps_overlapping_fragments_apart() {
+        // The second play would have started before the first was logged.
+        let entries = [
+            entry(1_000, 100, 600, "/a.mp3"),
+            entry(1_100, 200, 600, "/a.mp3"),
+        ];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 2);
+    }
+
+    #[test]
+    fn keeps_entries_with_a_different_length_apart() {
+        let entries = [
+            entry(1_000, 100, 600, "/a.mp3"),
+            entry(1_300, 200, 610, "/a.mp3"),
+        ];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 2);
+    }
+
+    #[test]
+    fn keeps_entries_without_a_length_apart() {
+        let entries = [entry(1_000, 10, 0, "/a.mp3"), entry(1_020, 10, 0, "/a.mp3")];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 2);
+    }
+
+    #[test]
+    fn only_joins_consecutive_entries() {
+        let entries = [
+            entry(1_000, 100, 600, "/a.mp3"),
+            entry(1_200, 100, 300, "/b.mp3"),
+            entry(1_400, 100, 600, "/a.mp3"),
+        ];
+        assert_eq!(coalesce_entries(&entries, PAUSE).len(), 3);
     }
-    let min_played_ms = (entry.total_ms / 2).min(240_000);
-    entry.elapsed_ms >= min_played_ms
 }
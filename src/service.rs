@@ -1,102 +1,405 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
 use anyhow::{Context, Result, bail};
+use reqwest::StatusCode;
 use reqwest::blocking::Client;
 use serde::Deserialize;
 use serde_json::Value;
 
-use crate::config::{Account, ServiceKeys};
+use crate::config::{Account, Config, ServiceKeys, get_service_keys};
+use crate::listenbrainz::{ListenBrainzClient, MAX_LISTENS_PER_REQUEST};
 use crate::scrobble::ScrobbleTrack;
 
-#[derive(Debug, Clone, Copy)]
+const MAX_SCROBBLES_PER_REQUEST: usize = 50;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+pub const BUILTIN_SERVICES: &[&str] = &["lastfm", "librefm", "listenbrainz"];
+
+// GNU FM (and therefore Libre.fm) accepts any API key and secret.
+const GNUFM_API_KEY: &str = "cobblestone";
+
+// Set when the user asks to stop at once. Submissions end before the next
+// request instead of mid-run, so the caller still queues what was not sent
+// and saves its state.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn interrupt_submissions() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Service {
     LastFm,
     LibreFm,
+    ListenBrainz,
+    Custom { base_url: String },
 }
 
 impl Service {
-    pub fn parse(value: &str) -> Result<Self> {
+    pub fn resolve(config: &Config, value: &str) -> Result<Self> {
         match value {
             "lastfm" => Ok(Service::LastFm),
             "librefm" => Ok(Service::LibreFm),
-            _ => bail!("Unsupported service: {value}"),
+            "listenbrainz" => Ok(Service::ListenBrainz),
+            _ => match get_service_keys(config, value).and_then(|keys| keys.base_url.clone()) {
+                Some(base_url) => Ok(Service::Custom { base_url }),
+                None => bail!("Unsupported service: {value}"),
+            },
         }
     }
 
-    pub fn base_url(self) -> &'static str {
+    pub fn base_url(&self) -> &str {
         match self {
             Service::LastFm => "https://ws.audioscrobbler.com/2.0/",
             Service::LibreFm => "https://libre.fm/2.0/",
+            Service::ListenBrainz => "https://api.listenbrainz.org/",
+            Service::Custom { base_url } => base_url,
+        }
+    }
+
+    pub fn auth_url(&self, api_key: &str, token: &str) -> String {
+        let site = match self {
+            Service::LastFm => "https://www.last.fm/",
+            _ => self
+                .base_url()
+                .trim_end_matches('/')
+                .trim_end_matches("2.0")
+                .trim_end_matches('/'),
+        };
+        format!(
+            "{}/api/auth/?api_key={api_key}&token={token}",
+            site.trim_end_matches('/')
+        )
+    }
+
+    pub fn uses_token(&self) -> bool {
+        matches!(self, Service::ListenBrainz)
+    }
+
+    pub fn keys(&self, config: &Config, name: &str) -> Option<ServiceKeys> {
+        match self {
+            Service::LibreFm => Some(ServiceKeys {
+                api_key: GNUFM_API_KEY.to_string(),
+                api_secret: GNUFM_API_KEY.to_string(),
+                base_url: None,
+            }),
+            Service::ListenBrainz => None,
+            Service::LastFm | Service::Custom { .. } => get_service_keys(config, name).cloned(),
+        }
+    }
+}
+
+pub fn default_custom_api_key() -> &'static str {
+    GNUFM_API_KEY
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Transient,
+    // The request may have reached the service before the connection failed
+    // or a gateway answered with a server error, so it is not sent again at
+    // once; like a transient failure, the plays are kept for a later run.
+    Unconfirmed,
+    InvalidSession,
+    Permanent,
+    // The service accepted the request but ignored this play; resubmitting
+    // it would be ignored again.
+    Rejected,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub kind: ErrorKind,
+    pub code: Option<u32>,
+    pub message: String,
+}
+
+impl ApiError {
+    fn from_code(code: u32, message: &str) -> Self {
+        // https://www.last.fm/api/errorcodes
+        let kind = match code {
+            8 | 11 | 16 | 29 => ErrorKind::Transient,
+            9 => ErrorKind::InvalidSession,
+            _ => ErrorKind::Permanent,
+        };
+        Self {
+            kind,
+            code: Some(code),
+            message: message.to_string(),
+        }
+    }
+
+    // A proxy in front of the service can answer 502 or 504 after passing a
+    // submission on, so a server error only shows that other requests failed.
+    pub fn from_status(status: StatusCode, submission: bool) -> Self {
+        let kind = if status.is_server_error() && submission {
+            ErrorKind::Unconfirmed
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            ErrorKind::Transient
+        } else {
+            ErrorKind::Permanent
+        };
+        Self {
+            kind,
+            code: None,
+            message: format!("HTTP {status}"),
+        }
+    }
+
+    // Only a failure to connect shows that a POST never reached the service.
+    pub fn transport(err: &reqwest::Error) -> Self {
+        let kind = if err.is_connect() {
+            ErrorKind::Transient
+        } else {
+            ErrorKind::Unconfirmed
+        };
+        Self {
+            kind,
+            code: None,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "API error {code}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+pub fn error_kind(err: &anyhow::Error) -> ErrorKind {
+    err.downcast_ref::<ApiError>()
+        .map_or(ErrorKind::Permanent, |err| err.kind)
+}
+
+// Runs `request` until it succeeds, fails with a non-transient error or
+// runs out of attempts, doubling the delay between attempts.
+pub fn with_retry<T>(what: &str, mut request: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match request() {
+            Ok(value) => return Ok(value),
+            Err(err)
+                if attempt < MAX_ATTEMPTS
+                    && error_kind(&err) == ErrorKind::Transient
+                    && !interrupted() =>
+            {
+                eprintln!("{what} failed ({err}); retrying in {}s", delay.as_secs());
+                thread::sleep(delay);
+                if interrupted() {
+                    return Err(err);
+                }
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScrobbleFailure {
+    pub index: usize,
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ScrobbleFailure {
+    // Only a play the service ignored is given up on. A failed request says
+    // nothing about its plays, even when the cause, such as a revoked key,
+    // will not go away by itself.
+    pub fn is_retryable(&self) -> bool {
+        self.kind != ErrorKind::Rejected
+    }
+}
+
+// A play the service ignored, from the per-entry part of its response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+pub enum ScrobbleClient {
+    Audioscrobbler(Box<AudioscrobblerClient>),
+    ListenBrainz(ListenBrainzClient),
+}
+
+impl ScrobbleClient {
+    pub fn new(
+        service: Service,
+        keys: Option<&ServiceKeys>,
+        account: &Account,
+        debug_response: bool,
+    ) -> Result<Self> {
+        if service.uses_token() {
+            return Ok(Self::ListenBrainz(ListenBrainzClient::new(
+                &service,
+                account,
+                debug_response,
+            )?));
+        }
+        let Some(keys) = keys else {
+            bail!("Missing API keys for {}", account.service);
+        };
+        Ok(Self::Audioscrobbler(Box::new(AudioscrobblerClient::new(
+            service,
+            keys,
+            account,
+            debug_response,
+        )?)))
+    }
+
+    pub fn scrobble_tracks(&mut self, tracks: &[ScrobbleTrack]) -> Vec<ScrobbleFailure> {
+        match self {
+            Self::Audioscrobbler(client) => {
+                scrobble_in_batches(tracks, MAX_SCROBBLES_PER_REQUEST, |batch| {
+                    client.scrobble_batch(batch)
+                })
+            }
+            Self::ListenBrainz(client) => {
+                scrobble_in_batches(tracks, MAX_LISTENS_PER_REQUEST, |batch| {
+                    client.submit_listens(batch)
+                })
+            }
+        }
+    }
+
+    pub fn session_key(&self) -> Option<&str> {
+        match self {
+            Self::Audioscrobbler(client) => Some(&client.session_key),
+            Self::ListenBrainz(_) => None,
+        }
+    }
+}
+
+// Submits `tracks` in chunks of `batch_size` and maps every rejected entry,
+// or every entry of a failed request, back to its index in `tracks`.
+fn scrobble_in_batches(
+    tracks: &[ScrobbleTrack],
+    batch_size: usize,
+    mut submit: impl FnMut(&[ScrobbleTrack]) -> Result<Vec<Option<Rejection>>>,
+) -> Vec<ScrobbleFailure> {
+    let mut failures = Vec::new();
+    for (batch_index, batch) in tracks.chunks(batch_size).enumerate() {
+        let offset = batch_index * batch_size;
+        if interrupted() {
+            failures.extend((offset..tracks.len()).map(|index| ScrobbleFailure {
+                index,
+                kind: ErrorKind::Transient,
+                message: "Interrupted before submission".to_string(),
+            }));
+            break;
+        }
+        match submit(batch) {
+            Ok(rejections) => {
+                for (index, rejection) in rejections.into_iter().enumerate() {
+                    if let Some(Rejection { kind, message }) = rejection {
+                        failures.push(ScrobbleFailure {
+                            index: offset + index,
+                            kind,
+                            message,
+                        });
+                    }
+                }
+            }
+            Err(err) => {
+                let kind = error_kind(&err);
+                for index in 0..batch.len() {
+                    failures.push(ScrobbleFailure {
+                        index: offset + index,
+                        kind,
+                        message: err.to_string(),
+                    });
+                }
+            }
         }
     }
+    failures
 }
 
-pub struct ScrobbleClient {
+pub struct AudioscrobblerClient {
     service: Service,
-    api_key: String,
-    api_secret: String,
+    keys: ServiceKeys,
+    account: Account,
     session_key: String,
     http: Client,
     debug_response: bool,
 }
 
-impl ScrobbleClient {
+impl AudioscrobblerClient {
     pub fn new(
         service: Service,
         keys: &ServiceKeys,
         account: &Account,
         debug_response: bool,
     ) -> Result<Self> {
-        let http = Client::builder()
-            .build()
-            .context("Failed building HTTP client")?;
-        let session_key =
-            fetch_mobile_session(&http, service, &keys.api_key, &keys.api_secret, account)?;
+        let http = http_client()?;
+        let session_key = match &account.session_key {
+            Some(session_key) => session_key.clone(),
+            None => fetch_mobile_session(&http, &service, keys, account)?,
+        };
         Ok(Self {
             service,
-            api_key: keys.api_key.clone(),
-            api_secret: keys.api_secret.clone(),
+            keys: keys.clone(),
+            account: account.clone(),
             session_key,
             http,
             debug_response,
         })
     }
 
-    pub fn scrobble_tracks(&self, tracks: &[ScrobbleTrack]) -> Vec<String> {
-        let mut errors = Vec::new();
-        for track in tracks {
-            if let Err(err) = self.scrobble_track(track) {
-                errors.push(format!("{} - {}: {}", track.artist, track.title, err));
+    fn scrobble_batch(&mut self, batch: &[ScrobbleTrack]) -> Result<Vec<Option<Rejection>>> {
+        match self.submit_batch(batch) {
+            Err(err) if error_kind(&err) == ErrorKind::InvalidSession => {
+                if self.account.password_md5.is_none() {
+                    return Err(err.context("Session is no longer valid; run `account login`"));
+                }
+                eprintln!(
+                    "Session for {} is no longer valid; requesting a new one",
+                    self.account.username
+                );
+                self.session_key =
+                    fetch_mobile_session(&self.http, &self.service, &self.keys, &self.account)?;
+                self.submit_batch(batch)
             }
+            result => result,
         }
-        errors
     }
 
-    fn scrobble_track(&self, track: &ScrobbleTrack) -> Result<()> {
+    fn submit_batch(&self, batch: &[ScrobbleTrack]) -> Result<Vec<Option<Rejection>>> {
         let mut params = vec![
             ("method".to_string(), "track.scrobble".to_string()),
-            ("artist".to_string(), track.artist.clone()),
-            ("track".to_string(), track.title.clone()),
-            ("timestamp".to_string(), track.timestamp.to_string()),
-            ("api_key".to_string(), self.api_key.clone()),
+            ("api_key".to_string(), self.keys.api_key.clone()),
             ("sk".to_string(), self.session_key.clone()),
         ];
-        if let Some(album) = &track.album {
-            params.push(("album".to_string(), album.clone()));
+        for (index, track) in batch.iter().enumerate() {
+            push_track_params(&mut params, index, track);
         }
-        if track.duration > 0 {
-            params.push(("duration".to_string(), track.duration.to_string()));
-        }
-        let api_sig = sign_params(&params, &self.api_secret);
+        let api_sig = sign_params(&params, &self.keys.api_secret);
         params.push(("api_sig".to_string(), api_sig));
         params.push(("format".to_string(), "json".to_string()));
-        let response = self
-            .http
-            .post(self.service.base_url())
-            .form(&params)
-            .send()
-            .context("Failed sending scrobble request")?;
-        let text = response
-            .text()
-            .context("Failed reading scrobble response")?;
+        let text = post_form(
+            &self.http,
+            self.service.base_url(),
+            &params,
+            "Scrobble request",
+            true,
+        )?;
         if self.debug_response {
             eprintln!(
                 "Scrobble response from {}: {}",
@@ -104,47 +407,127 @@ impl ScrobbleClient {
                 text
             );
         }
-        check_api_error(&text)?;
-        check_scrobble_result(&text)?;
-        Ok(())
+        Ok(scrobble_rejections(&text, batch.len()))
     }
 }
 
+fn push_track_params(params: &mut Vec<(String, String)>, index: usize, track: &ScrobbleTrack) {
+    let mut push = |name: &str, value: String| params.push((format!("{name}[{index}]"), value));
+    push("artist", track.artist.clone());
+    push("track", track.title.clone());
+    push("timestamp", track.timestamp.to_string());
+    if let Some(album) = &track.album {
+        push("album", album.clone());
+    }
+    if let Some(album_artist) = &track.album_artist {
+        push("albumArtist", album_artist.clone());
+    }
+    if let Some(track_number) = track.track_number {
+        push("trackNumber", track_number.to_string());
+    }
+    if let Some(mbid) = &track.mbid {
+        push("mbid", mbid.clone());
+    }
+    if track.duration > 0 {
+        push("duration", track.duration.to_string());
+    }
+    push(
+        "chosenByUser",
+        if track.chosen_by_user { "1" } else { "0" }.to_string(),
+    );
+}
+
+fn http_client() -> Result<Client> {
+    Client::builder()
+        .build()
+        .context("Failed building HTTP client")
+}
+
+pub fn fetch_password_session(
+    service: &Service,
+    keys: &ServiceKeys,
+    account: &Account,
+) -> Result<String> {
+    fetch_mobile_session(&http_client()?, service, keys, account)
+}
+
 fn fetch_mobile_session(
     http: &Client,
-    service: Service,
-    api_key: &str,
-    api_secret: &str,
+    service: &Service,
+    keys: &ServiceKeys,
     account: &Account,
 ) -> Result<String> {
+    let Some(password_md5) = &account.password_md5 else {
+        bail!(
+            "No password or session stored for {}; run `account login`",
+            account.username
+        );
+    };
     let auth_token = format!(
         "{:x}",
-        md5::compute(format!("{}{}", account.username, account.password_md5))
+        md5::compute(format!("{}{}", account.username, password_md5))
     );
-    let mut params = vec![
+    let params = vec![
         ("method".to_string(), "auth.getMobileSession".to_string()),
         ("username".to_string(), account.username.clone()),
         ("authToken".to_string(), auth_token),
-        ("api_key".to_string(), api_key.to_string()),
     ];
-    let api_sig = sign_params(&params, api_secret);
+    let json = signed_call(http, service, keys, params, "Session request")?;
+    let (_name, key) = session_from_response(&json)?;
+    Ok(key)
+}
+
+pub fn request_auth_token(service: &Service, keys: &ServiceKeys) -> Result<String> {
+    let params = vec![("method".to_string(), "auth.getToken".to_string())];
+    let json = signed_call(&http_client()?, service, keys, params, "Token request")?;
+    json.get("token")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("Missing token in response"))
+}
+
+// Exchanges a token the user approved in the browser for a session key,
+// returning the account name together with the key.
+pub fn fetch_web_session(
+    service: &Service,
+    keys: &ServiceKeys,
+    token: &str,
+) -> Result<(String, String)> {
+    let params = vec![
+        ("method".to_string(), "auth.getSession".to_string()),
+        ("token".to_string(), token.to_string()),
+    ];
+    let json = signed_call(&http_client()?, service, keys, params, "Session request")?;
+    session_from_response(&json)
+}
+
+fn signed_call(
+    http: &Client,
+    service: &Service,
+    keys: &ServiceKeys,
+    mut params: Vec<(String, String)>,
+    what: &str,
+) -> Result<Value> {
+    params.push(("api_key".to_string(), keys.api_key.clone()));
+    let api_sig = sign_params(&params, &keys.api_secret);
     params.push(("api_sig".to_string(), api_sig));
     params.push(("format".to_string(), "json".to_string()));
-    let response = http
-        .post(service.base_url())
-        .form(&params)
-        .send()
-        .context("Failed requesting mobile session")?;
-    let text = response.text().context("Failed reading session response")?;
-    check_api_error(&text)?;
-    let json: Value = serde_json::from_str(&text).context("Failed parsing session response")?;
-    let key = json
+    let text = post_form(http, service.base_url(), &params, what, false)?;
+    serde_json::from_str(&text).with_context(|| format!("Failed parsing {what} response"))
+}
+
+fn session_from_response(json: &Value) -> Result<(String, String)> {
+    let session = json
         .get("session")
-        .and_then(|session| session.get("key"))
-        .and_then(|value| value.as_str())
-        .map(str::to_string)
-        .ok_or_else(|| anyhow::anyhow!("Missing session key in response"))?;
-    Ok(key)
+        .ok_or_else(|| anyhow::anyhow!("Missing session in response"))?;
+    let field = |name: &str| {
+        session
+            .get(name)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Missing session {name} in response"))
+    };
+    Ok((field("name")?, field("key")?))
 }
 
 fn sign_params(params: &[(String, String)], secret: &str) -> String {
@@ -159,66 +542,111 @@ fn sign_params(params: &[(String, String)], secret: &str) -> String {
     format!("{:x}", md5::compute(signature))
 }
 
-fn check_api_error(payload: &str) -> Result<()> {
-    let json: Value = serde_json::from_str(payload).context("Failed parsing API response")?;
+fn post_form(
+    http: &Client,
+    url: &str,
+    params: &[(String, String)],
+    what: &str,
+    submission: bool,
+) -> Result<String> {
+    with_retry(what, || {
+        let response = http
+            .post(url)
+            .form(params)
+            .send()
+            .map_err(|err| ApiError::transport(&err))?;
+        let status = response.status();
+        let text = response.text().map_err(|err| ApiError::transport(&err))?;
+        match serde_json::from_str::<Value>(&text) {
+            Ok(json) => check_api_error(&json)?,
+            Err(_) if !status.is_success() => {
+                return Err(ApiError::from_status(status, submission).into());
+            }
+            Err(err) => return Err(err).context("Failed parsing API response"),
+        }
+        if !status.is_success() {
+            return Err(ApiError::from_status(status, submission).into());
+        }
+        Ok(text)
+    })
+}
+
+fn check_api_error(json: &Value) -> Result<()> {
     if let Some(error) = json.get("error") {
         let message = json
             .get("message")
             .and_then(|value| value.as_str())
             .unwrap_or("API error");
-        bail!("API error {error}: {message}");
+        let Some(code) = parse_u32_value(error) else {
+            bail!("API error {error}: {message}");
+        };
+        return Err(ApiError::from_code(code, message).into());
     }
     Ok(())
 }
 
-fn check_scrobble_result(payload: &str) -> Result<()> {
+// Returns one entry per submitted track, in submission order: `None` when the
+// scrobble was accepted, or why it was ignored.
+fn scrobble_rejections(payload: &str, count: usize) -> Vec<Option<Rejection>> {
     if let Ok(parsed) = serde_json::from_str::<ScrobbleResponse>(payload) {
-        return check_scrobble_from_struct(&parsed);
+        return rejections_from_struct(&parsed, count);
     }
     if let Ok(parsed) = serde_json::from_str::<Value>(payload) {
-        return check_scrobble_from_value(&parsed);
+        return rejections_from_value(&parsed, count);
     }
-    Ok(())
+    vec![None; count]
 }
 
-fn check_scrobble_from_struct(parsed: &ScrobbleResponse) -> Result<()> {
+fn rejections_from_struct(parsed: &ScrobbleResponse, count: usize) -> Vec<Option<Rejection>> {
     let Some(scrobbles) = parsed.scrobbles.as_ref() else {
-        return Ok(());
+        return vec![None; count];
     };
     let accepted = scrobbles.attr.as_ref().map_or(0, |attr| attr.accepted);
     let ignored = scrobbles.attr.as_ref().map_or(0, |attr| attr.ignored);
     if accepted > 0 && ignored == 0 {
-        return Ok(());
-    }
-    let ignored = scrobbles
-        .scrobble
-        .as_ref()
-        .and_then(ScrobbleEntries::first_ignored_message);
-    let (ignored_code, ignored_message) = match ignored {
-        Some(IgnoredMessageField::Object(message)) => {
-            let code = message
-                .code
-                .clone()
-                .unwrap_or_else(|| "unknown".to_string());
-            let text = message
-                .text
-                .clone()
-                .unwrap_or_else(|| "Scrobble rejected".to_string());
-            (code, text)
-        }
-        Some(IgnoredMessageField::Text(message)) => ("unknown".to_string(), message.clone()),
-        Some(IgnoredMessageField::Number(code)) => ("unknown".to_string(), code.to_string()),
-        None => ("unknown".to_string(), "Scrobble rejected".to_string()),
-    };
-    if ignored_code == "91" {
-        return Ok(());
+        return vec![None; count];
     }
-    bail!("Scrobble rejected (code {ignored_code}): {ignored_message}");
+    let entries: Vec<&ScrobbleEntry> = match &scrobbles.scrobble {
+        Some(ScrobbleEntries::One(entry)) => vec![entry],
+        Some(ScrobbleEntries::Many(entries)) => entries.iter().collect(),
+        None => Vec::new(),
+    };
+    (0..count)
+        .map(|index| {
+            let ignored = entries
+                .get(index)
+                .and_then(|entry| entry.ignored_message.as_ref());
+            let (code, message) = match ignored {
+                Some(IgnoredMessageField::Object(message)) => {
+                    let code = message
+                        .code
+                        .clone()
+                        .unwrap_or_else(|| "unknown".to_string());
+                    let text = message
+                        .text
+                        .clone()
+                        .unwrap_or_else(|| "Scrobble rejected".to_string());
+                    (code, text)
+                }
+                Some(IgnoredMessageField::Text(message)) => {
+                    ("unknown".to_string(), message.clone())
+                }
+                Some(IgnoredMessageField::Number(code)) => {
+                    ("unknown".to_string(), code.to_string())
+                }
+                None if entries.is_empty() => {
+                    ("unknown".to_string(), "Scrobble rejected".to_string())
+                }
+                None => return None,
+            };
+            rejection(&code, &message)
+        })
+        .collect()
 }
 
-fn check_scrobble_from_value(parsed: &Value) -> Result<()> {
+fn rejections_from_value(parsed: &Value, count: usize) -> Vec<Option<Rejection>> {
     let Some(scrobbles) = parsed.get("scrobbles") else {
-        return Ok(());
+        return vec![None; count];
     };
     let attr = scrobbles.get("@attr");
     let accepted = attr
@@ -230,18 +658,41 @@ fn check_scrobble_from_value(parsed: &Value) -> Result<()> {
         .and_then(parse_u32_value)
         .unwrap_or(0);
     if accepted > 0 && ignored == 0 {
-        return Ok(());
+        return vec![None; count];
     }
-    let ignored_message = scrobbles
+    let entries = scrobbles
         .get("scrobble")
-        .and_then(first_scrobble_value)
-        .and_then(|value| value.get("ignoredMessage"))
-        .map_or_else(
-            || ("unknown".to_string(), "Scrobble rejected".to_string()),
-            ignored_message_from_value,
-        );
-    let (code, message) = ignored_message;
-    bail!("Scrobble rejected (code {code}): {message}");
+        .map(scrobble_values)
+        .unwrap_or_default();
+    (0..count)
+        .map(|index| {
+            let (code, message) = match entries.get(index) {
+                Some(entry) => entry
+                    .get("ignoredMessage")
+                    .map(ignored_message_from_value)?,
+                None if entries.is_empty() => {
+                    ("unknown".to_string(), "Scrobble rejected".to_string())
+                }
+                None => return None,
+            };
+            rejection(&code, &message)
+        })
+        .collect()
+}
+
+// Last.fm ignores a scrobble with code 1 or 2 (artist or track ignored), 3 or
+// 4 (timestamp too old or too new) or 5 (daily scrobble limit exceeded); only
+// the limit clears by itself.
+fn rejection(code: &str, message: &str) -> Option<Rejection> {
+    let kind = match code {
+        "0" | "91" => return None,
+        "5" => ErrorKind::Transient,
+        _ => ErrorKind::Rejected,
+    };
+    Some(Rejection {
+        kind,
+        message: format!("Scrobble rejected (code {code}): {message}"),
+    })
 }
 
 fn parse_u32_value(value: &Value) -> Option<u32> {
@@ -251,14 +702,14 @@ fn parse_u32_value(value: &Value) -> Option<u32> {
     value.as_str().and_then(|raw| raw.parse::<u32>().ok())
 }
 
-fn first_scrobble_value(value: &Value) -> Option<&Value> {
+fn scrobble_values(value: &Value) -> Vec<&Value> {
     if let Some(array) = value.as_array() {
-        return array.first();
+        return array.iter().collect();
     }
     if value.is_object() {
-        return Some(value);
+        return vec![value];
     }
-    None
+    Vec::new()
 }
 
 fn ignored_message_from_value(value: &Value) -> (String, String) {
@@ -309,17 +760,6 @@ enum ScrobbleEntries {
     Many(Vec<ScrobbleEntry>),
 }
 
-impl ScrobbleEntries {
-    fn first_ignored_message(&self) -> Option<&IgnoredMessageField> {
-        match self {
-            ScrobbleEntries::One(entry) => entry.ignored_message.as_ref(),
-            ScrobbleEntries::Many(entries) => entries
-                .first()
-                .and_then(|entry| entry.ignored_message.as_ref()),
-        }
-    }
-}
-
 #[derive(Debug, Deserialize)]
 struct ScrobbleEntry {
     #[serde(rename = "ignoredMessage")]
@@ -359,3 +799,87 @@ where
         StringOrU32::Number(value) => Ok(value),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses `payload` both ways a scrobble response is read and checks they
+    // agree.
+    fn rejections(payload: &str, count: usize) -> Vec<Option<Rejection>> {
+        let from_struct = rejections_from_struct(&serde_json::from_str(payload).unwrap(), count);
+        let from_value = rejections_from_value(&serde_json::from_str(payload).unwrap(), count);
+        assert_eq!(from_struct, from_value);
+        assert_eq!(scrobble_rejections(payload, count), from_struct);
+        from_struct
+    }
+
+    fn rejected(code: &str, message: &str) -> Rejection {
+        Rejection {
+            kind: ErrorKind::Rejected,
+            message: format!("Scrobble rejected (code {code}): {message}"),
+        }
+    }
+
+    #[test]
+    fn accepts_a_batch_without_ignored_scrobbles() {
+        let payload = r##"{"scrobbles": {
+            "@attr": {"accepted": "2", "ignored": "0"},
+            "scrobble": [
+                {"ignoredMessage": {"code": "0", "#text": ""}},
+                {"ignoredMessage": {"code": "0", "#text": ""}}
+            ]
+        }}"##;
+        assert_eq!(rejections(payload, 2), vec![None, None]);
+    }
+
+    #[test]
+    fn rejects_only_the_ignored_entries_of_a_batch() {
+        let payload = r##"{"scrobbles": {
+            "@attr": {"accepted": 2, "ignored": 1},
+            "scrobble": [
+                {"ignoredMessage": {"code": "0", "#text": ""}},
+                {"ignoredMessage": {"code": "1", "#text": "Artist ignored"}},
+                {"ignoredMessage": {"code": "0", "#text": ""}}
+            ]
+        }}"##;
+        assert_eq!(
+            rejections(payload, 3),
+            vec![None, Some(rejected("1", "Artist ignored")), None]
+        );
+    }
+
+    #[test]
+    fn reads_a_single_scrobble_object() {
+        let payload = r##"{"scrobbles": {
+            "@attr": {"accepted": "0", "ignored": "1"},
+            "scrobble": {"ignoredMessage": {"code": "3", "#text": "Timestamp too old"}}
+        }}"##;
+        assert_eq!(
+            rejections(payload, 1),
+            vec![Some(rejected("3", "Timestamp too old"))]
+        );
+    }
+
+    #[test]
+    fn rejects_every_play_when_ignored_entries_are_missing() {
+        let payload = r#"{"scrobbles": {"@attr": {"accepted": 0, "ignored": 2}}}"#;
+        assert_eq!(
+            rejections(payload, 2),
+            vec![
+                Some(rejected("unknown", "Scrobble rejected")),
+                Some(rejected("unknown", "Scrobble rejected")),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_plays_held_back_by_the_daily_limit_retryable() {
+        let payload = r##"{"scrobbles": {
+            "@attr": {"accepted": "0", "ignored": "1"},
+            "scrobble": {"ignoredMessage": {"code": "5", "#text": "Daily scrobble limit exceeded"}}
+        }}"##;
+        let rejection = rejections(payload, 1).remove(0).unwrap();
+        assert_eq!(rejection.kind, ErrorKind::Transient);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::config::{state_file_name, state_path, write_private_file};
+
+// The device log is moved into a local spool before anything else happens, so
+// unplugging the player mid-run can no longer lose plays: the spool is only
+// cleared once every play in it was delivered, queued, skipped as ineligible
+// or ignored by the service.
+pub fn spool_path(config_path: &Path, id: &str) -> PathBuf {
+    state_path(config_path, "spool").join(format!("{}.log", state_file_name(id)))
+}
+
+// Returns the spooled lines followed by the device lines after `skip` bytes
+// that are not spooled yet, and how many lines came from the device.
+pub fn merge_playback_log(
+    playback_path: &Path,
+    spool_path: &Path,
+    skip: u64,
+) -> Result<(String, usize)> {
+    let mut merged = if spool_path.exists() {
+        fs::read_to_string(spool_path)
+            .with_context(|| format!("Failed reading spool {}", spool_path.display()))?
+    } else {
+        String::new()
+    };
+    let device_log = fs::read(playback_path)
+        .with_context(|| format!("Failed reading playback log {}", playback_path.display()))?;
+    let skip = usize::try_from(skip).map_or(device_log.len(), |skip| skip.min(device_log.len()));
+    let device_log = String::from_utf8_lossy(&device_log[skip..]);
+    // A run interrupted between spooling and truncating leaves the same lines
+    // in both places.
+    let mut spooled: HashSet<String> = merged.lines().map(str::to_string).collect();
+    let mut added = 0;
+    for line in device_log.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || !spooled.insert(line.to_string()) {
+            continue;
+        }
+        merged.push_str(line);
+        merged.push('\n');
+        added += 1;
+    }
+    Ok((merged, added))
+}
+
+pub fn spool_playback_log(playback_path: &Path, spool_path: &Path, skip: u64) -> Result<usize> {
+    let (merged, added) = merge_playback_log(playback_path, spool_path, skip)?;
+    if added > 0 {
+        write_private_file(spool_path, merged.as_bytes())
+            .with_context(|| format!("Failed writing spool {}", spool_path.display()))?;
+    }
+    truncate_playback_log(playback_path)?;
+    Ok(added)
+}
+
+pub fn clear_spool(spool_path: &Path) -> Result<()> {
+    if spool_path.exists() {
+        fs::remove_file(spool_path)
+            .with_context(|| format!("Failed removing spool {}", spool_path.display()))?;
+    }
+    Ok(())
+}
+
+fn truncate_playback_log(path: &Path) -> Result<()> {
+    File::create(path)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed truncating {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    // A scratch directory holding a device log and its spool, removed when
+    // dropped.
+    struct TempSpool(PathBuf);
+
+    impl TempSpool {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cobblestone-{name}-{}", process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn playback_path(&self) -> PathBuf {
+            self.0.join("playback.log")
+        }
+
+        fn spool_path(&self) -> PathBuf {
+            self.0.join("spool.log")
+        }
+    }
+
+    impl Drop for TempSpool {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn does_not_duplicate_lines_left_by_an_interrupted_run() {
+        let dir = TempSpool::new("spool-interrupted");
+        fs::write(dir.playback_path(), "1:a\n2:b\n").unwrap();
+        // The spool was written, but the run stopped before truncating the
+        // device log, which has since gained a line.
+        let (spooled, added) =
+            merge_playback_log(&dir.playback_path(), &dir.spool_path(), 0).unwrap();
+        assert_eq!(added, 2);
+        fs::write(dir.spool_path(), &spooled).unwrap();
+        fs::write(dir.playback_path(), "1:a\n2:b\n3:c\n").unwrap();
+
+        let added = spool_playback_log(&dir.playback_path(), &dir.spool_path(), 0).unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(
+            fs::read_to_string(dir.spool_path()).unwrap(),
+            "1:a\n2:b\n3:c\n"
+        );
+        assert_eq!(fs::read_to_string(dir.playback_path()).unwrap(), "");
+    }
+
+    #[test]
+    fn leaves_out_lines_a_checkpoint_covers() {
+        let dir = TempSpool::new("spool-skip");
+        fs::write(dir.playback_path(), "1:a\n2:b\n3:c\n").unwrap();
+        let added = spool_playback_log(&dir.playback_path(), &dir.spool_path(), 8).unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(fs::read_to_string(dir.spool_path()).unwrap(), "3:c\n");
+    }
+}
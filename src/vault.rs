@@ -0,0 +1,94 @@
+use anyhow::{Context, Result, anyhow, bail};
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+pub const PASSPHRASE_ENV: &str = "COBBLESTONE_PASSPHRASE";
+
+const KDF: &str = "argon2id";
+const CIPHER: &str = "xchacha20poly1305";
+const SALT_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vault {
+    pub kdf: String,
+    pub cipher: String,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<Vault> {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Failed encrypting credentials"))?;
+    Ok(Vault {
+        kdf: KDF.to_string(),
+        cipher: CIPHER.to_string(),
+        salt: STANDARD.encode(salt),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+pub fn open(vault: &Vault, passphrase: &str) -> Result<Vec<u8>> {
+    if vault.kdf != KDF || vault.cipher != CIPHER {
+        bail!(
+            "Unsupported credential vault ({} / {})",
+            vault.kdf,
+            vault.cipher
+        );
+    }
+    let salt = STANDARD
+        .decode(&vault.salt)
+        .context("Invalid credential vault salt")?;
+    let nonce = STANDARD
+        .decode(&vault.nonce)
+        .context("Invalid credential vault nonce")?;
+    if nonce.len() != 24 {
+        bail!("Invalid credential vault nonce");
+    }
+    let ciphertext = STANDARD
+        .decode(&vault.ciphertext)
+        .context("Invalid credential vault data")?;
+    let cipher = XChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    cipher
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted credential vault"))
+}
+
+pub fn read_passphrase(confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if passphrase.is_empty() {
+            bail!("{PASSPHRASE_ENV} is set but empty.");
+        }
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Config passphrase: ")?;
+    if confirm {
+        let again = rpassword::prompt_password("Confirm passphrase: ")?;
+        if passphrase != again {
+            bail!("Passphrases do not match.");
+        }
+    }
+    if passphrase.is_empty() {
+        bail!("Passphrase must not be empty.");
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("Failed deriving vault key: {err}"))?;
+    Ok(key)
+}